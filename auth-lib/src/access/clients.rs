@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use serde::{Deserialize, Deserializer, de::Error};
+
+use crate::db::parser::client::OAuthClient;
+use super::tokens::TokenHasher;
+
+/// OAuth clients registered from the environment at startup
+///
+/// Clients with redirect URIs but no secret are public clients, e.g. single page or mobile apps.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ClientConfig {
+    // Credentials as `client_id:client_secret`
+    #[serde(default, deserialize_with = "credentials")]
+    pub clients: HashMap<String, String>,
+    // Redirect URIs of the authorization code flow, as `client_id=uri`
+    #[serde(default, deserialize_with = "redirect_uris")]
+    pub redirect_uris: HashMap<String, Vec<String>>,
+}
+
+fn redirect_uris<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, Vec<String>>, D::Error> {
+    let entries = Vec::<String>::deserialize(deserializer)?;
+    let mut uris: HashMap<String, Vec<String>> = HashMap::new();
+
+    for entry in entries {
+        match entry.split_once('=') {
+            Some((id, uri)) if !id.is_empty() && !uri.is_empty() => {
+                uris.entry(id.to_string()).or_default().push(uri.to_string());
+            }
+            _ => {
+                return Err(D::Error::custom(format!("expected client_id=uri, got {}", entry)));
+            }
+        }
+    }
+    Ok(uris)
+}
+
+fn credentials<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, String>, D::Error> {
+    let entries = Vec::<String>::deserialize(deserializer)?;
+    let mut clients = HashMap::new();
+
+    for entry in entries {
+        match entry.split_once(':') {
+            Some((id, secret)) if !id.is_empty() && !secret.is_empty() => {
+                clients.insert(id.to_string(), secret.to_string());
+            }
+            _ => {
+                return Err(D::Error::custom(format!("expected client_id:client_secret, got {}", entry)));
+            }
+        }
+    }
+    Ok(clients)
+}
+
+impl ClientConfig {
+    /// Clients to register or update at startup, see `OAuthClient::seed`
+    ///
+    /// Clients with redirect URIs may use the authorization code flow, none may request scopes.
+    pub fn seeds(&self, hasher: &TokenHasher) -> Vec<OAuthClient> {
+        let mut ids: Vec<&String> = self.clients.keys().chain(self.redirect_uris.keys()).collect();
+        ids.sort();
+        ids.dedup();
+
+        ids.into_iter()
+            .map(|id| {
+                let redirect_uris = self.redirect_uris.get(id).cloned().unwrap_or_default();
+
+                OAuthClient {
+                    client_id: id.clone(),
+                    name: None,
+                    secret_hash: self.clients.get(id).map(|secret| hasher.hash(secret)),
+                    jwks: None,
+                    grant_types: if redirect_uris.is_empty() { Vec::new() } else { vec!["authorization_code".to_string(), "refresh_token".to_string()] },
+                    redirect_uris,
+                    scopes: Vec::new(),
+                    audiences: Vec::new(),
+                    client_type: None,
+                    access_lifetime: None,
+                    refresh_lifetime: None,
+                    disabled: false,
+                    created: Utc::now(),
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::HashSet;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{revocation::Revocations, tokens::TokenConfig};
+
+// Algorithms of DPoP proofs, only asymmetric ones prove the possession of a private key
+pub const DPOP_ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::ES256, Algorithm::ES384,
+    Algorithm::RS256, Algorithm::RS384, Algorithm::RS512,
+    Algorithm::PS256, Algorithm::PS384, Algorithm::PS512,
+    Algorithm::EdDSA,
+];
+
+/// DPoP proofs of possession (RFC 9449), should be parsed in environment
+#[derive(Debug, Deserialize, Clone)]
+pub struct DpopConfig {
+    // Refuse access tokens which aren't bound to a key, bound tokens always need a proof
+    #[serde(default)]
+    pub required: bool,
+    // Seconds a proof is accepted after its `iat`
+    #[serde(default = "default_proof_lifetime")]
+    pub proof_lifetime: i64,
+    // Key of the server nonces, proofs must carry a fresh nonce when it is set
+    pub nonce_secret: Option<String>,
+    // Seconds a server nonce is handed out, the previous one is still accepted
+    #[serde(default = "default_nonce_lifetime")]
+    pub nonce_lifetime: i64,
+}
+
+fn default_proof_lifetime() -> i64 {
+    60
+}
+
+fn default_nonce_lifetime() -> i64 {
+    5 * 60
+}
+
+impl Default for DpopConfig {
+    fn default() -> Self {
+        DpopConfig {
+            required: false,
+            proof_lifetime: default_proof_lifetime(),
+            nonce_secret: None,
+            nonce_lifetime: default_nonce_lifetime(),
+        }
+    }
+}
+
+impl DpopConfig {
+    fn window(&self) -> i64 {
+        Utc::now().timestamp() / self.nonce_lifetime.max(1)
+    }
+
+    /// Nonces are keyed hashes of a time window, so every node sharing the secret accepts them
+    fn nonce_at(secret: &str, window: i64) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(window.to_string().as_bytes());
+
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    /// Current server nonce, when nonces are required
+    pub fn nonce(&self) -> Option<String> {
+        self.nonce_secret.as_ref().map(|secret| Self::nonce_at(secret, self.window()))
+    }
+
+    fn nonce_valid(&self, nonce: Option<&str>) -> bool {
+        let secret = match &self.nonce_secret {
+            Some(s) => s,
+            None => return true,
+        };
+        let window = self.window();
+
+        nonce.map(|n| [window, window - 1].iter().any(|w| Self::nonce_at(secret, *w) == n)).unwrap_or(false)
+    }
+}
+
+/// Confirmation claim binding a token to the key of a DPoP proof (RFC 9449 section 6.1)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Confirmation {
+    // JWK thumbprint of the key
+    pub jkt: String,
+}
+
+/// Valid proof of possession of a key
+#[derive(Debug, Clone)]
+pub struct Proof {
+    // JWK thumbprint of the key
+    pub jkt: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProofClaims {
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    // Hash of the access token the proof is sent with
+    #[serde(default)]
+    ath: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+}
+
+/// Invalid or missing proof, with a fresh server nonce when the proof lacked a valid one
+#[derive(Debug)]
+pub struct DpopError {
+    pub error: &'static str,
+    pub description: &'static str,
+    pub nonce: Option<String>,
+}
+
+impl DpopError {
+    pub fn new(error: &'static str, description: &'static str) -> Self {
+        DpopError {
+            error,
+            description,
+            nonce: None,
+        }
+    }
+
+    pub fn invalid(description: &'static str) -> Self {
+        Self::new("invalid_dpop_proof", description)
+    }
+
+    /// Error of a protected resource, challenging the client to use the DPoP scheme (RFC 9449 section 7.1)
+    pub fn challenge(&self) -> HttpResponse {
+        let algs: Vec<String> = DPOP_ALGORITHMS.iter().map(|a| format!("{:?}", a)).collect();
+        let mut res = HttpResponse::Unauthorized();
+
+        res.insert_header(("WWW-Authenticate", format!("DPoP error=\"{}\", error_description=\"{}\", algs=\"{}\"", self.error, self.description, algs.join(" "))));
+
+        if let Some(nonce) = &self.nonce {
+            res.insert_header(("DPoP-Nonce", nonce.clone()));
+        }
+        res.body(self.description)
+    }
+}
+
+/// JWK thumbprint (RFC 7638), the SHA-256 of the key's required members in lexicographic order
+pub fn thumbprint(jwk: &serde_json::Value) -> Option<String> {
+    let member = |name: &str| jwk.get(name).and_then(|v| v.as_str());
+
+    let canonical = match member("kty")? {
+        "EC" => serde_json::json!({ "crv": member("crv")?, "kty": "EC", "x": member("x")?, "y": member("y")? }),
+        "RSA" => serde_json::json!({ "e": member("e")?, "kty": "RSA", "n": member("n")? }),
+        "OKP" => serde_json::json!({ "crv": member("crv")?, "kty": "OKP", "x": member("x")? }),
+        _ => return None,
+    };
+
+    Some(URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.to_string().as_bytes())))
+}
+
+/// Hash of an access token as carried in the `ath` claim of proofs
+pub fn ath(access_token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(access_token.as_bytes()))
+}
+
+/// URLs the request may be named by in `htu`: as received, or under the issuer when it is a URL
+fn request_urls(req: &HttpRequest) -> Vec<String> {
+    let info = req.connection_info();
+    let mut urls = vec![format!("{}://{}{}", info.scheme(), info.host(), req.path())];
+
+    if let Some(config) = req.app_data::<web::Data<TokenConfig>>() {
+        if config.issuer.starts_with("https://") || config.issuer.starts_with("http://") {
+            urls.push(format!("{}{}", config.issuer.trim_end_matches('/'), req.path()));
+        }
+    }
+    urls
+}
+
+/// Public key in the header of a proof, which must not carry private members
+fn header_jwk(proof: &str) -> Option<serde_json::Value> {
+    let header: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(proof.split('.').next()?).ok()?).ok()?;
+    let jwk = header.get("jwk")?;
+
+    if jwk.get("d").is_some() {
+        return None;
+    }
+    Some(jwk.clone())
+}
+
+/// Validate the DPoP proof of the request (RFC 9449 section 4.3), None when the request has none
+///
+/// Proofs sent with an access token must carry its hash. Each proof can only be used once, which
+/// the `Revocations` list registered in app_data keeps track of, proofs are refused without it.
+pub fn verify_proof(req: &HttpRequest, access_token: Option<&str>) -> Result<Option<Proof>, DpopError> {
+    let default = DpopConfig::default();
+    let config = req.app_data::<web::Data<DpopConfig>>().map(|c| c.get_ref()).unwrap_or(&default);
+    let leeway = req.app_data::<web::Data<TokenConfig>>().map(|c| c.leeway as i64).unwrap_or(0);
+
+    let mut headers = req.headers().get_all("DPoP");
+    let proof = match (headers.next(), headers.next()) {
+        (None, _) => return Ok(None),
+        (Some(p), None) => p.to_str().map_err(|_| DpopError::invalid("Invalid DPoP proof!"))?,
+        _ => {
+            return Err(DpopError::invalid("Only one DPoP proof may be sent!"));
+        }
+    };
+
+    // Without a replay store a captured proof could be used again
+    let revocations = req.app_data::<web::Data<Revocations>>()
+        .ok_or(DpopError::invalid("DPoP isn't supported, proofs can't be checked for replay!"))?;
+
+    let header = decode_header(proof).map_err(|_| DpopError::invalid("Invalid DPoP proof!"))?;
+
+    if header.typ.as_deref() != Some("dpop+jwt") {
+        return Err(DpopError::invalid("DPoP proof must have the dpop+jwt type!"));
+    }
+    if !DPOP_ALGORITHMS.contains(&header.alg) {
+        return Err(DpopError::invalid("Unsupported DPoP proof algorithm!"));
+    }
+
+    let jkt = header_jwk(proof).as_ref().and_then(thumbprint).ok_or(DpopError::invalid("DPoP proof must carry a public key!"))?;
+    let key = header.jwk.as_ref().and_then(|jwk| DecodingKey::from_jwk(jwk).ok()).ok_or(DpopError::invalid("DPoP proof must carry a public key!"))?;
+
+    // Proofs are short-lived by their `iat`, they have no `exp`
+    let mut validation = Validation::new(header.alg);
+    validation.required_spec_claims = HashSet::new();
+    validation.validate_exp = false;
+
+    let claims = decode::<ProofClaims>(proof, &key, &validation).map_err(|_| DpopError::invalid("Invalid DPoP proof signature!"))?.claims;
+
+    if claims.htm != req.method().as_str() {
+        return Err(DpopError::invalid("DPoP proof was made for another method!"));
+    }
+
+    let htu = claims.htu.split(['?', '#']).next().unwrap_or_default();
+    if !request_urls(req).iter().any(|url| url == htu) {
+        return Err(DpopError::invalid("DPoP proof was made for another URL!"));
+    }
+
+    let now = Utc::now().timestamp();
+    if claims.iat > now + leeway || claims.iat < now - config.proof_lifetime - leeway {
+        return Err(DpopError::invalid("DPoP proof is too old or issued in the future!"));
+    }
+
+    if let Some(token) = access_token {
+        if claims.ath.as_deref() != Some(ath(token).as_str()) {
+            return Err(DpopError::invalid("DPoP proof wasn't made for this access token!"));
+        }
+    }
+
+    if !config.nonce_valid(claims.nonce.as_deref()) {
+        let mut err = DpopError::new("use_dpop_nonce", "A fresh DPoP nonce is required!");
+        err.nonce = config.nonce();
+        return Err(err);
+    }
+
+    // A proof can only be used once while it is accepted
+    let exp = (claims.iat + config.proof_lifetime + leeway) as usize;
+
+    if !revocations.use_once(&format!("dpop:{}:{}", jkt, claims.jti), exp) {
+        return Err(DpopError::invalid("DPoP proof was already used!"));
+    }
+
+    Ok(Some(Proof { jkt }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::Method, test::TestRequest};
+    use jsonwebtoken::{encode, jwk::{AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, OctetKeyPairParameters, OctetKeyPairType}, EncodingKey, Header};
+    use crate::access::revocation::MemoryStore;
+
+    const HTU: &str = "http://auth.example/oauth/token";
+
+    fn testdata(file: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), file)).unwrap()
+    }
+
+    fn proof(htm: &str, htu: &str, ath: Option<&str>) -> String {
+        // The raw Ed25519 key ends the SubjectPublicKeyInfo
+        let pem = String::from_utf8(testdata("ed25519.pub.pem")).unwrap();
+        let der = base64::engine::general_purpose::STANDARD.decode(pem.lines().filter(|l| !l.starts_with("-----")).collect::<String>()).unwrap();
+
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.typ = Some("dpop+jwt".to_string());
+        header.jwk = Some(Jwk {
+            common: CommonParameters::default(),
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(&der[der.len() - 32..]),
+            }),
+        });
+
+        let claims = serde_json::json!({
+            "jti": uuid::Uuid::new_v4().to_string(),
+            "htm": htm,
+            "htu": htu,
+            "iat": Utc::now().timestamp(),
+            "ath": ath,
+        });
+
+        encode(&header, &claims, &EncodingKey::from_ed_pem(&testdata("ed25519.pem")).unwrap()).unwrap()
+    }
+
+    fn request(proof: &str, revocations: Option<&web::Data<Revocations>>) -> HttpRequest {
+        let mut req = TestRequest::default()
+            .method(Method::POST)
+            .uri("/oauth/token")
+            .insert_header(("Host", "auth.example"))
+            .insert_header(("DPoP", proof));
+
+        if let Some(revocations) = revocations {
+            req = req.app_data(revocations.clone());
+        }
+        req.to_http_request()
+    }
+
+    fn revocations() -> web::Data<Revocations> {
+        web::Data::new(Revocations::new(MemoryStore::default()))
+    }
+
+    #[test]
+    fn accepts_a_proof_once() {
+        let revocations = revocations();
+        let proof = proof("POST", HTU, None);
+
+        let accepted = verify_proof(&request(&proof, Some(&revocations)), None).unwrap().unwrap();
+        assert_eq!(Some(accepted.jkt), header_jwk(&proof).as_ref().and_then(thumbprint));
+
+        assert!(verify_proof(&request(&proof, Some(&revocations)), None).is_err());
+    }
+
+    #[test]
+    fn refuses_proofs_without_a_replay_store() {
+        assert!(verify_proof(&request(&proof("POST", HTU, None), None), None).is_err());
+    }
+
+    #[test]
+    fn refuses_proofs_for_another_request() {
+        let revocations = revocations();
+
+        assert!(verify_proof(&request(&proof("GET", HTU, None), Some(&revocations)), None).is_err());
+        assert!(verify_proof(&request(&proof("POST", "http://auth.example/oauth/revoke", None), Some(&revocations)), None).is_err());
+    }
+
+    #[test]
+    fn binds_proofs_to_the_access_token() {
+        let revocations = revocations();
+
+        assert!(verify_proof(&request(&proof("POST", HTU, None), Some(&revocations)), Some("token")).is_err());
+        assert!(verify_proof(&request(&proof("POST", HTU, Some(&ath("other"))), Some(&revocations)), Some("token")).is_err());
+        assert!(verify_proof(&request(&proof("POST", HTU, Some(&ath("token"))), Some(&revocations)), Some("token")).is_ok());
+    }
+
+    #[test]
+    fn requests_without_a_proof_have_none() {
+        let req = TestRequest::default().to_http_request();
+
+        assert!(verify_proof(&req, None).unwrap().is_none());
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Deserializer, de::Error};
+
+// Grant type of the token exchange (RFC 8693 section 2.1)
+pub const EXCHANGE_GRANT: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+pub const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+/// Audience a client may exchange tokens for, and the scopes it may request for it
+#[derive(Debug, Clone)]
+pub struct ExchangePolicy {
+    pub audience: String,
+    pub scopes: Vec<String>,
+}
+
+/// Which clients may exchange tokens on behalf of their subject, should be parsed in environment
+#[derive(Debug, Deserialize, Clone)]
+pub struct ExchangeConfig {
+    // Policies as `client:audience:scope+scope`, the scopes may be left empty and contain colons
+    #[serde(default, deserialize_with = "policies")]
+    pub policies: HashMap<String, Vec<ExchangePolicy>>,
+    // Longest lifetime of an exchanged token in seconds, it never outlives the subject token
+    #[serde(default = "default_max_lifetime")]
+    pub max_lifetime: i64,
+    // How many actors may be chained in the `act` claim
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+}
+
+fn default_max_lifetime() -> i64 {
+    5 * 60
+}
+
+fn default_max_depth() -> usize {
+    1
+}
+
+impl Default for ExchangeConfig {
+    fn default() -> Self {
+        ExchangeConfig {
+            policies: HashMap::new(),
+            max_lifetime: default_max_lifetime(),
+            max_depth: default_max_depth(),
+        }
+    }
+}
+
+fn policies<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, Vec<ExchangePolicy>>, D::Error> {
+    let entries = Vec::<String>::deserialize(deserializer)?;
+    let mut policies: HashMap<String, Vec<ExchangePolicy>> = HashMap::new();
+
+    for entry in entries {
+        let parts: Vec<&str> = entry.splitn(3, ':').collect();
+
+        if parts.len() != 3 || parts[0].is_empty() || parts[1].is_empty() {
+            return Err(D::Error::custom(format!("expected client:audience:scopes, got {}", entry)));
+        }
+
+        policies.entry(parts[0].to_string()).or_default().push(ExchangePolicy {
+            audience: parts[1].to_string(),
+            scopes: parts[2].split('+').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect(),
+        });
+    }
+    Ok(policies)
+}
+
+impl ExchangePolicy {
+    /// Scopes of the exchanged token: those requested, or all of the policy, among those granted to the subject token
+    ///
+    /// A subject token without any scope grants none. None when a requested scope isn't allowed.
+    pub fn scopes(&self, granted: Option<&str>, requested: Option<&str>) -> Option<Vec<String>> {
+        let granted: Vec<&str> = granted.unwrap_or_default().split_whitespace().collect();
+        let allowed = |scope: &str| self.scopes.iter().any(|p| p == scope) && granted.contains(&scope);
+
+        match requested {
+            Some(requested) if !requested.split_whitespace().all(allowed) => None,
+            Some(requested) => Some(requested.split_whitespace().map(|s| s.to_string()).collect()),
+            None => Some(self.scopes.iter().filter(|s| allowed(s)).cloned().collect()),
+        }
+    }
+}
+
+impl ExchangeConfig {
+    /// Policy letting the client exchange tokens for the audience
+    pub fn policy(&self, client_id: &str, audience: &str) -> Option<&ExchangePolicy> {
+        self.policies.get(client_id)?.iter().find(|p| p.audience == audience)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(policies: &[&str]) -> Result<ExchangeConfig, serde_json::Error> {
+        serde_json::from_value(serde_json::json!({ "policies": policies }))
+    }
+
+    #[test]
+    fn parses_policies_with_colons_in_scopes() {
+        let config = config(&["gateway:grades:grades:read+grades:write", "gateway:library:"]).unwrap();
+
+        assert_eq!(config.policy("gateway", "grades").unwrap().scopes, vec!["grades:read", "grades:write"]);
+        assert!(config.policy("gateway", "library").unwrap().scopes.is_empty());
+        assert!(config.policy("other", "grades").is_none());
+    }
+
+    #[test]
+    fn only_passes_on_scopes_of_the_subject() {
+        let config = config(&["gateway:grades:grades:read+grades:write"]).unwrap();
+        let policy = config.policy("gateway", "grades").unwrap();
+
+        assert_eq!(policy.scopes(Some("openid grades:read"), None), Some(vec!["grades:read".to_string()]));
+        assert_eq!(policy.scopes(Some("grades:read"), Some("grades:write")), None);
+        assert_eq!(policy.scopes(Some("grades:read grades:write"), Some("grades:write")), Some(vec!["grades:write".to_string()]));
+    }
+
+    #[test]
+    fn subjects_without_scopes_grant_none() {
+        let config = config(&["gateway:grades:grades:read+grades:write"]).unwrap();
+        let policy = config.policy("gateway", "grades").unwrap();
+
+        assert_eq!(policy.scopes(None, None), Some(Vec::new()));
+        assert_eq!(policy.scopes(None, Some("grades:write")), None);
+    }
+
+    #[test]
+    fn refuses_incomplete_policies() {
+        assert!(config(&["gateway:grades"]).is_err());
+        assert!(config(&[":grades:read"]).is_err());
+    }
+}
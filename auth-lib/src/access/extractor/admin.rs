@@ -0,0 +1,64 @@
+use actix_web::{http, error, web, FromRequest};
+use std::future::{Ready, ready};
+use subtle::ConstantTimeEq;
+use super::{super::{roles::{Admin as AdminRole, Role, RoleConfig}, tokens::Secret}, extract::{decode_access, TokenKind}};
+
+/// Marker for requests authenticated with the administration key, or the access token of an administrator
+#[derive(Debug)]
+pub struct Admin {
+    // UUID of the administrator, None when the administration key was used
+    pub user: Option<String>,
+}
+
+/// Trait to validate the administration key or an admin role token from the Authorization header
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        // The administration key is only accepted when one is configured
+        let admin_key = req.app_data::<web::Data<Secret>>().and_then(|s| s.admin.clone());
+
+        let _header = req.headers().get(http::header::AUTHORIZATION);
+
+        let _key = match _header {
+            Some(h) => h.to_str().unwrap_or("").trim_start_matches("Bearer ").to_string(),
+            None => {
+                return ready(Err(error::ErrorUnauthorized("Authorization header missing!")));
+            }
+        };
+
+        if let Some(admin_key) = &admin_key {
+            if !_key.is_empty() && bool::from(_key.as_bytes().ct_eq(admin_key.as_bytes())) {
+                return ready(Ok(Admin { user: None }));
+            }
+        }
+
+        // Otherwise the access token of a user granted the admin role
+        let claims = match decode_access(req) {
+            Ok(c) => c,
+            Err(_) if admin_key.is_some() => {
+                return ready(Err(error::ErrorUnauthorized("Invalid administration key!")));
+            }
+            Err(err) => {
+                return ready(Err(err));
+            }
+        };
+
+        let granted = match req.app_data::<web::Data<RoleConfig>>() {
+            Some(config) => config.grants(&claims.roles, AdminRole::NAME),
+            None => RoleConfig::default().grants(&claims.roles, AdminRole::NAME),
+        };
+
+        if claims.kind != TokenKind::User || !granted {
+            return ready(Err(error::ErrorForbidden("Administrator role required!")));
+        }
+
+        // Only first-party logins administer, not tokens issued to OAuth clients nor exchanged by a service
+        if claims.client_id.is_some() || claims.act.is_some() {
+            return ready(Err(error::ErrorForbidden("Administration needs a token of a first-party login!")));
+        }
+
+        ready(Ok(Admin { user: Some(claims.sub) }))
+    }
+}
@@ -1,85 +1,301 @@
 use serde::{Serialize, Deserialize};
-use jsonwebtoken::{decode, Algorithm, Validation, DecodingKey};
-use actix_web::{http::{self}, error, web, FromRequest};
+use jsonwebtoken::Algorithm;
+use actix_web::{http::{self}, error::{self, InternalError}, web, FromRequest};
 use std::future::{Ready, ready};
-use super::super::tokens::Secret;
+use super::super::{dpop::{verify_proof, Confirmation, DpopConfig, DpopError}, keyring::{KeyRing, KeyUse}, keys::{KeyConfig, Keys}, revocation::Revocations, tokenize::format::Format, tokens::{Secret, TokenConfig}};
+
+/// Kind of subject an access token was issued to
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    // A signed-in user, tokens issued before the claim was added are user tokens
+    #[default]
+    User,
+    // A service client of the client_credentials grant
+    Service,
+}
+
+/// Party acting on behalf of the subject of an exchanged token (RFC 8693 section 4.1)
+///
+/// Earlier actors of a chain of exchanges are nested in `act`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Actor {
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Box<Actor>>,
+}
+
+impl Actor {
+    /// Number of actors in the chain
+    pub fn depth(&self) -> usize {
+        1 + self.act.as_ref().map(|a| a.depth()).unwrap_or(0)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AccessClaims {
+    pub iss: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aud: Vec<String>,
+    pub sub: String,        // User's UUID, stable across username changes, or the client id of service tokens
+    pub iat: usize,
+    pub nbf: usize,
+    pub jti: String,
+    // Session the token was issued for, empty for tokens which don't belong to one
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sid: String,
     pub exp: usize,
-    pub custom: Token,
+    #[serde(default)]
+    pub kind: TokenKind,
+    // Client the token was issued to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    // Space separated scopes granted to the token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    // Service acting on behalf of the subject, for exchanged tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    // Key the token is bound to, it must be presented with a DPoP proof of that key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+    // Roles of the user, as assigned when the token was issued
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    // Only user tokens carry a profile, service tokens leave it out
+    #[serde(default, skip_serializing_if = "Profile::is_empty")]
+    pub custom: Profile,
 }
 
+/// User data carried in access tokens
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Profile {
+    pub username: String,
+    pub name: String,
+}
+
+impl Profile {
+    pub fn is_empty(&self) -> bool {
+        self.username.is_empty()
+    }
+}
+
+/// User of a validated access token
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Token {
+    pub sub: String,
+    pub jti: String,
+    pub sid: String,
+    pub exp: usize,
     pub username: String,
     pub name: String,
+    // Client the token was issued to, None for first-party logins
+    pub client_id: Option<String>,
+    // Permissions of the user for first-party logins, the scopes granted to the client otherwise
+    pub scopes: Vec<String>,
+    // Service calling on behalf of the user, for exchanged tokens
+    pub act: Option<Actor>,
+    // Roles assigned to the user, without the ones they imply
+    pub roles: Vec<String>,
 }
 
-/// Trait to validate and retrieve User Data from JWT
-impl FromRequest for Token {
-    type Error = actix_web::Error;
-    type Future = Ready<Result<Self, Self::Error>>;
-    
-    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
-        // Get Header from Request
-        let _header = req.headers().get(http::header::AUTHORIZATION);
+impl Token {
+    /// Issued to a login of the service itself, not to an OAuth client nor through a token exchange
+    pub fn is_first_party(&self) -> bool {
+        self.client_id.is_none() && self.act.is_none()
+    }
+}
 
-        match _header {
-            Some(_) => {}
-            None => {
-                return ready(Err(error::ErrorUnauthorized("Authorization header missing!")));
-            }
+/// Service client of a validated access token from the client_credentials grant
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Service {
+    pub client_id: String,
+    pub jti: String,
+    pub exp: usize,
+    pub aud: Vec<String>,
+    pub scopes: Vec<String>,
+    // Service calling on behalf of this one, for exchanged tokens
+    pub act: Option<Actor>,
+}
+
+impl Service {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+/// Format of the tokens verified without a key ring, from the KeyConfig in app_data or JWT
+fn fallback_format(req: &actix_web::HttpRequest) -> Format {
+    req.app_data::<web::Data<KeyConfig>>().map(|c| c.format).unwrap_or_default()
+}
+
+/// Validate the access token of the request with the keys and checks from app_data
+pub(crate) fn decode_access(req: &actix_web::HttpRequest) -> Result<AccessClaims, actix_web::Error> {
+    // Get Header from Request
+    let _header = match req.headers().get(http::header::AUTHORIZATION) {
+        Some(h) => h,
+        None => {
+            return Err(error::ErrorUnauthorized("Authorization header missing!"));
         }
+    };
 
-        // Get JWT from Header
-        let _token = _header.unwrap().to_str().unwrap_or("").to_string();
-        if _token.is_empty() {
-            return ready(Err(error::ErrorUnauthorized("Empty access token provided!")));
+    // Get JWT from Header, with the Bearer or DPoP scheme of OAuth clients or without any
+    let _header = _header.to_str().unwrap_or("");
+    let _dpop = _header.strip_prefix("DPoP ");
+    let _token = _dpop.or_else(|| _header.strip_prefix("Bearer ")).unwrap_or(_header).trim().to_string();
+    if _token.is_empty() {
+        return Err(error::ErrorUnauthorized("Empty access token provided!"));
+    }
+
+    // Get claim checks from app_data, the defaults only check the issuer and expiry
+    let validation = match req.app_data::<web::Data<TokenConfig>>() {
+        Some(config) => config.validation(),
+        None => TokenConfig::default().validation(),
+    };
+
+    // Get verification keys from app_data: the key ring in its token format, or a single key or the shared HS256 secret
+    let data = if let Some(ring) = req.app_data::<web::Data<KeyRing>>() {
+        ring.decode::<AccessClaims>(&_token, KeyUse::Access, &validation)
+    } else if let Some(keys) = req.app_data::<web::Data<Keys>>() {
+        fallback_format(req).decode::<AccessClaims>(&_token, keys, &validation)
+    } else if let Some(secret) = req.app_data::<web::Data<Secret>>() {
+        fallback_format(req).decode::<AccessClaims>(&_token, &Keys::from_secret(Algorithm::HS256, &secret.access), &validation)
+    } else {
+        return Err(error::ErrorBadRequest("Missing secret key!"));
+    };
+
+    match data {
+        Ok(t) => {
+            // Check the revocation list when one is registered in app_data
+            if let Some(revocations) = req.app_data::<web::Data<Revocations>>() {
+                if revocations.rejects(&t.claims.jti, &t.claims.sid) {
+                    return Err(error::ErrorUnauthorized("Token revoked!"));
+                }
+            }
+            check_binding(req, &t.claims, &_token, _dpop.is_some())?;
+            Ok(t.claims)
         }
-        
-        // Get Access Secret from app_data
-        let _secret = req.app_data::<web::Data<Secret>>();
+        Err(err) => {
+            if let jsonwebtoken::errors::ErrorKind::ExpiredSignature = err.clone().into_kind() {
+                return Err(error::ErrorUnauthorized("Token timed out!"));
+            }
+            Err(error::ErrorUnauthorized("Invalid token!"))
+        }
+    }
+}
 
-        match _secret {
-            Some(_) => {}
-            None => {
-                return ready(Err(error::ErrorBadRequest("Missing secret key!")));
+/// Bound tokens need the DPoP scheme and a proof of their key, unbound tokens are refused when DPoP is required
+fn check_binding(req: &actix_web::HttpRequest, claims: &AccessClaims, token: &str, dpop_scheme: bool) -> Result<(), actix_web::Error> {
+    let required = req.app_data::<web::Data<DpopConfig>>().map(|c| c.required).unwrap_or(false);
+    let challenge = |err: DpopError| -> actix_web::Error { InternalError::from_response(err.description, err.challenge()).into() };
+
+    match &claims.cnf {
+        Some(cnf) => {
+            if !dpop_scheme {
+                return Err(challenge(DpopError::new("invalid_token", "Token is bound to a key, use the DPoP scheme!")));
+            }
+
+            match verify_proof(req, Some(token)) {
+                Ok(Some(proof)) if proof.jkt == cnf.jkt => Ok(()),
+                Ok(Some(_)) => Err(challenge(DpopError::invalid("DPoP proof wasn't made with the token's key!"))),
+                Ok(None) => Err(challenge(DpopError::invalid("DPoP proof missing!"))),
+                Err(err) => Err(challenge(err)),
             }
         }
+        None if dpop_scheme => Err(challenge(DpopError::new("invalid_token", "Token isn't bound to a key!"))),
+        None if required => Err(challenge(DpopError::new("invalid_token", "A DPoP bound token is required!"))),
+        None => Ok(()),
+    }
+}
 
-        let access_secret = _secret.unwrap().access.as_str();
-        
-        let data = decode::<AccessClaims>(
-            &_token,
-            &DecodingKey::from_secret(access_secret.as_ref()),
-            &Validation::new(Algorithm::HS256),
-        );
-        
-        // Decode the token return
-        match data {
-            Ok(t) => {
-                return ready(
+/// Trait to validate and retrieve User Data from JWT, service tokens are refused
+impl FromRequest for Token {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    
+    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let claims = match decode_access(req) {
+            Ok(c) => c,
+            Err(err) => {
+                return ready(Err(err));
+            }
+        };
+
+        match claims.kind {
+            TokenKind::User if !claims.custom.is_empty() => {
+                ready(
                     Ok(
                         Token {
-                            username: t.claims.custom.username,
-                            name: t.claims.custom.name,
+                            sub: claims.sub,
+                            jti: claims.jti,
+                            sid: claims.sid,
+                            exp: claims.exp,
+                            username: claims.custom.username,
+                            name: claims.custom.name,
+                            client_id: claims.client_id,
+                            scopes: claims.scope.unwrap_or_default().split_whitespace().map(|s| s.to_string()).collect(),
+                            act: claims.act,
+                            roles: claims.roles,
                         }
                     )
-                );
+                )
             }
+            TokenKind::User => ready(Err(error::ErrorUnauthorized("Invalid token!"))),
+            TokenKind::Service => ready(Err(error::ErrorForbidden("A user token is required!"))),
+        }
+    }
+}
+
+/// Trait to validate and retrieve the Service client from JWT, user tokens are refused
+impl FromRequest for Service {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let claims = match decode_access(req) {
+            Ok(c) => c,
             Err(err) => {
-                if let jsonwebtoken::errors::ErrorKind::ExpiredSignature = err.clone().into_kind() {
-                    return ready(Err(error::ErrorUnauthorized("Token timed out!")));
-                }
-                return ready(Err(error::ErrorUnauthorized("Invalid token!")));
-                }
+                return ready(Err(err));
+            }
+        };
+
+        if claims.kind != TokenKind::Service {
+            return ready(Err(error::ErrorForbidden("A service token is required!")));
         }
+
+        ready(
+            Ok(
+                Service {
+                    client_id: claims.client_id.unwrap_or(claims.sub),
+                    jti: claims.jti,
+                    exp: claims.exp,
+                    aud: claims.aud,
+                    scopes: claims.scope.unwrap_or_default().split_whitespace().map(|s| s.to_string()).collect(),
+                    act: claims.act,
+                }
+            )
+        )
     }
 }
 
+/// Registered claims default to empty for refresh tokens issued before they were added
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RefreshClaims {
+    #[serde(default)]
+    pub iss: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aud: Vec<String>,
+    #[serde(default)]
+    pub sub: String,
+    #[serde(default)]
+    pub iat: usize,
+    #[serde(default)]
+    pub nbf: usize,
+    #[serde(default)]
+    pub jti: String,
+    // Session the token belongs to, all of its rotated tokens share it
+    #[serde(default)]
+    pub sid: String,
     pub exp: usize,         // Required (validate_exp defaults to true in validation). Expiration time (as UTC timestamp)
     pub id: Info,
 }
@@ -111,38 +327,42 @@ impl FromRequest for Info {
             return ready(Err(error::ErrorUnauthorized("Empty access token provided!")));
         }
         
-        // Get Access Secret from app_data
-        let _secret = req.app_data::<web::Data<Secret>>();
-
-        match _secret {
-            Some(_) => {}
-            None => {
-                return ready(Err(error::ErrorBadRequest("Missing secret key!")));
-            }
-        }
+        // Get claim checks from app_data
+        let validation = match req.app_data::<web::Data<TokenConfig>>() {
+            Some(config) => config.refresh_validation(),
+            None => TokenConfig::default().refresh_validation(),
+        };
 
-        let access_secret = _secret.unwrap().refresh.as_str();
-        
-        let data = decode::<RefreshClaims>(
-            &_token,
-            &DecodingKey::from_secret(access_secret.as_ref()),
-            &Validation::new(Algorithm::HS256),
-        );
+        // Get verification keys from app_data: the key ring in its token format, or the shared HS256 secret
+        let data = if let Some(ring) = req.app_data::<web::Data<KeyRing>>() {
+            ring.decode::<RefreshClaims>(&_token, KeyUse::Refresh, &validation)
+        } else if let Some(secret) = req.app_data::<web::Data<Secret>>() {
+            fallback_format(req).decode::<RefreshClaims>(&_token, &Keys::from_secret(Algorithm::HS256, &secret.refresh), &validation)
+        } else {
+            return ready(Err(error::ErrorBadRequest("Missing secret key!")));
+        };
         
         // Decode the token return
         match data {
             Ok(t) => {
-                return ready(
+                // Check the revocation list when one is registered in app_data
+                if let Some(revocations) = req.app_data::<web::Data<Revocations>>() {
+                    if revocations.rejects(&t.claims.jti, &t.claims.sid) {
+                        return ready(Err(error::ErrorUnauthorized("Token revoked!")));
+                    }
+                }
+
+                ready(
                     Ok(
                         Info {
                             username: t.claims.id.username,
                         }
                     )
-                );
+                )
             }
             Err(_) => {
-                return ready(Err(error::ErrorUnauthorized("Invalid token!")));
-                }
+                ready(Err(error::ErrorUnauthorized("Invalid token!")))
+            }
         }
     }
 }
\ No newline at end of file
@@ -1 +1,4 @@
-pub mod extract;
\ No newline at end of file
+pub mod extract;
+pub mod admin;
+pub mod role;
+pub mod scope;
\ No newline at end of file
@@ -0,0 +1,100 @@
+use actix_web::{error, web, FromRequest};
+use std::{future::{Ready, ready}, marker::PhantomData, ops::Deref};
+use super::{super::roles::{Role, RoleConfig}, extract::Token};
+
+/// User token granted the role `R`, directly or through the hierarchy of RoleConfig
+///
+/// Handlers declare it as `RequireRole<Teacher>` and read the user through it like a `Token`.
+/// Only tokens of a first-party login carry roles, those of OAuth clients and exchanged tokens are refused.
+#[derive(Debug)]
+pub struct RequireRole<R: Role> {
+    pub token: Token,
+    role: PhantomData<R>,
+}
+
+impl<R: Role> Deref for RequireRole<R> {
+    type Target = Token;
+
+    fn deref(&self) -> &Token {
+        &self.token
+    }
+}
+
+/// Trait to validate the user token and check its roles, 403 when the role is missing
+impl<R: Role> FromRequest for RequireRole<R> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let token = match Token::from_request(req, payload).into_inner() {
+            Ok(t) => t,
+            Err(err) => {
+                return ready(Err(err));
+            }
+        };
+
+        if !token.is_first_party() {
+            return ready(Err(error::ErrorForbidden(format!("Role {} is required, from a first-party login!", R::NAME))));
+        }
+
+        // Get the role hierarchy from app_data, roles imply nothing without it
+        let granted = match req.app_data::<web::Data<RoleConfig>>() {
+            Some(config) => config.grants(&token.roles, R::NAME),
+            None => RoleConfig::default().grants(&token.roles, R::NAME),
+        };
+
+        if !granted {
+            return ready(Err(error::ErrorForbidden(format!("Role {} is required!", R::NAME))));
+        }
+
+        ready(Ok(RequireRole { token, role: PhantomData }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App, HttpResponse};
+
+    use crate::{access::{extractor::extract::AccessClaims, keyring::KeyUse, roles::Admin, tokens::TokenConfig}, api::testing};
+
+    async fn admin_only(_admin: RequireRole<Admin>) -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    async fn call(token: String) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(testing::ring()))
+                .app_data(web::Data::new(TokenConfig::default()))
+                .route("/", web::get().to(admin_only))
+        ).await;
+
+        let req = test::TestRequest::get().uri("/").insert_header(("Authorization", format!("Bearer {}", token))).to_request();
+        test::call_service(&app, req).await.status()
+    }
+
+    #[actix_web::test]
+    async fn grants_roles_to_first_party_logins() {
+        let user = testing::user(&["admin"]);
+
+        assert_eq!(call(testing::access_token(&user, &testing::session(&user, None, None))).await, StatusCode::OK);
+        assert_eq!(call(testing::access_token(&testing::user(&[]), &testing::session(&user, None, None))).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn refuses_tokens_of_clients_and_exchanges() {
+        let user = testing::user(&["admin"]);
+        let first_party = testing::session(&user, None, None);
+
+        assert_eq!(call(testing::access_token(&user, &testing::session(&user, Some("lms"), Some("openid")))).await, StatusCode::FORBIDDEN);
+        assert_eq!(call(testing::exchanged_token(&user, &first_party)).await, StatusCode::FORBIDDEN);
+
+        // Even when such a token names roles
+        let ring = testing::ring();
+        let mut claims = ring.decode::<AccessClaims>(&testing::access_token(&user, &first_party), KeyUse::Access, &TokenConfig::default().validation()).unwrap().claims;
+        claims.client_id = Some("lms".to_string());
+
+        assert_eq!(call(ring.encode(&claims, KeyUse::Access).unwrap()).await, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,52 @@
+use actix_web::{error::InternalError, HttpResponse, FromRequest};
+use std::{future::{Ready, ready}, marker::PhantomData, ops::Deref};
+use super::{super::roles::Scope, extract::{decode_access, AccessClaims}};
+
+/// Access token of a user or service granted every scope of `S`
+///
+/// Handlers declare it as `RequireScope<GradesWrite>` and read the claims of the token through it.
+#[derive(Debug)]
+pub struct RequireScope<S: Scope> {
+    pub claims: AccessClaims,
+    scope: PhantomData<S>,
+}
+
+impl<S: Scope> Deref for RequireScope<S> {
+    type Target = AccessClaims;
+
+    fn deref(&self) -> &AccessClaims {
+        &self.claims
+    }
+}
+
+/// 403 naming the required scopes in the challenge (RFC 6750 section 3.1)
+fn insufficient_scope(scheme: &str, scopes: &[&str]) -> HttpResponse {
+    HttpResponse::Forbidden()
+        .insert_header(("WWW-Authenticate", format!("{} error=\"insufficient_scope\", error_description=\"The token lacks a required scope\", scope=\"{}\"", scheme, scopes.join(" "))))
+        .body(format!("Scope {} is required!", scopes.join(" ")))
+}
+
+/// Trait to validate the access token and check its scopes
+impl<S: Scope> FromRequest for RequireScope<S> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let claims = match decode_access(req) {
+            Ok(c) => c,
+            Err(err) => {
+                return ready(Err(err));
+            }
+        };
+
+        let granted: Vec<&str> = claims.scope.as_deref().unwrap_or_default().split_whitespace().collect();
+
+        if S::SCOPES.iter().any(|s| !granted.contains(s)) {
+            // Bound tokens are used with the DPoP scheme
+            let scheme = if claims.cnf.is_some() { "DPoP" } else { "Bearer" };
+            return ready(Err(InternalError::from_response("Insufficient scope!", insufficient_scope(scheme, S::SCOPES)).into()));
+        }
+
+        ready(Ok(RequireScope { claims, scope: PhantomData }))
+    }
+}
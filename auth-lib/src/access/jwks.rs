@@ -0,0 +1,80 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::{AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters, Jwk, JwkSet, OctetKeyPairParameters, PublicKeyUse, RSAKeyParameters}, Algorithm};
+use sha2::{Digest, Sha256};
+
+use super::{keyring::{KeyRing, KeyUse}, keys::PublicKey};
+
+fn b64(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn curve(algorithm: Algorithm) -> EllipticCurve {
+    match algorithm {
+        Algorithm::ES384 => EllipticCurve::P384,
+        _ => EllipticCurve::P256,
+    }
+}
+
+impl PublicKey {
+    /// JWK thumbprint of the key (RFC 7638), used as its `kid`
+    pub fn thumbprint(&self) -> String {
+        // Required members only, in lexicographic order and without whitespace
+        let canonical = match self {
+            PublicKey::Rsa { n, e } => format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, b64(e), b64(n)),
+            PublicKey::Ec { curve: alg, x, y } => {
+                let crv = if *alg == Algorithm::ES384 { "P-384" } else { "P-256" };
+                format!(r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#, crv, b64(x), b64(y))
+            }
+            PublicKey::Ed { x } => format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, b64(x)),
+        };
+
+        b64(&Sha256::digest(canonical.as_bytes()))
+    }
+
+    /// Public JWK describing the key for signature verification
+    pub fn to_jwk(&self, algorithm: Algorithm, kid: &str) -> Jwk {
+        let params = match self {
+            PublicKey::Rsa { n, e } => AlgorithmParameters::RSA(RSAKeyParameters {
+                n: b64(n),
+                e: b64(e),
+                ..Default::default()
+            }),
+            PublicKey::Ec { curve: alg, x, y } => AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                curve: curve(*alg),
+                x: b64(x),
+                y: b64(y),
+                ..Default::default()
+            }),
+            PublicKey::Ed { x } => AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                curve: EllipticCurve::Ed25519,
+                x: b64(x),
+                ..Default::default()
+            }),
+        };
+
+        Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                algorithm: Some(algorithm),
+                key_id: Some(kid.to_string()),
+                ..Default::default()
+            },
+            algorithm: params,
+        }
+    }
+}
+
+/// Build the JSON Web Key Set published to token consumers
+///
+/// Pending, active and retired access keys are published. HMAC secrets never are.
+pub fn key_set(ring: &KeyRing) -> JwkSet {
+    let keys = ring.published(KeyUse::Access)
+        .iter()
+        .filter_map(|k| match (&k.public, &k.kid) {
+            (Some(public), Some(kid)) => Some(public.to_jwk(k.algorithm, kid)),
+            _ => None,
+        })
+        .collect();
+
+    JwkSet { keys }
+}
@@ -0,0 +1,402 @@
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{errors::{Error, ErrorKind}, Algorithm, Header, TokenData, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{keys::{KeyConfig, KeyError, Keys}, tokenize::format::Format, tokens::Secret};
+
+/// Lifecycle of a key in the ring
+///
+/// Pending keys are published but don't sign yet, active keys sign new tokens,
+/// retired keys only verify outstanding tokens and expired keys are dropped.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyState {
+    Pending,
+    Active,
+    Retired,
+    Expired,
+}
+
+/// Which kind of token a key signs
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyUse {
+    Access,
+    Refresh,
+}
+
+/// Key entry of the ring manifest
+///
+/// The schedule moves the key from pending to active at `activate_at`,
+/// to retired at `retire_at` and to expired at `expire_at`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct KeyEntry {
+    pub kid: String,
+    pub algorithm: Algorithm,
+
+    pub private_key: Option<String>,
+    pub public_key: Option<String>,
+    // Shared secret for HMAC algorithms
+    pub secret: Option<String>,
+
+    pub activate_at: Option<DateTime<Utc>>,
+    pub retire_at: Option<DateTime<Utc>>,
+    pub expire_at: Option<DateTime<Utc>>,
+}
+
+/// Manifest listing the keys of each token type
+#[derive(Deserialize, Debug, Clone)]
+pub struct Manifest {
+    #[serde(default)]
+    pub access: Vec<KeyEntry>,
+    #[serde(default)]
+    pub refresh: Vec<KeyEntry>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct KeyStatus {
+    pub kid: String,
+    #[serde(rename = "use")]
+    pub key_use: KeyUse,
+    pub algorithm: Algorithm,
+    pub state: KeyState,
+}
+
+struct RingKey {
+    keys: Arc<Keys>,
+    key_use: KeyUse,
+
+    activate_at: Option<DateTime<Utc>>,
+    retire_at: Option<DateTime<Utc>>,
+    expire_at: Option<DateTime<Utc>>,
+
+    // Set by an admin command, takes precedence over the schedule
+    forced: Option<KeyState>,
+}
+
+impl RingKey {
+    fn new(key_use: KeyUse, keys: Keys) -> Self {
+        RingKey {
+            keys: Arc::new(keys),
+            key_use,
+            activate_at: None,
+            retire_at: None,
+            expire_at: None,
+            forced: None,
+        }
+    }
+
+    fn from_entry(key_use: KeyUse, entry: KeyEntry) -> Result<Self, KeyError> {
+        let keys = Keys::from_parts(
+            entry.algorithm,
+            entry.private_key.as_deref(),
+            entry.public_key.as_deref(),
+            entry.secret.as_deref(),
+        )?;
+
+        Ok(RingKey {
+            keys: Arc::new(keys.with_kid(entry.kid)),
+            key_use,
+            activate_at: entry.activate_at,
+            retire_at: entry.retire_at,
+            expire_at: entry.expire_at,
+            forced: None,
+        })
+    }
+
+    fn state(&self, now: DateTime<Utc>) -> KeyState {
+        if let Some(state) = self.forced {
+            return state;
+        }
+
+        let reached = |at: Option<DateTime<Utc>>| at.map(|at| now >= at).unwrap_or(false);
+
+        if reached(self.expire_at) {
+            KeyState::Expired
+        } else if reached(self.retire_at) {
+            KeyState::Retired
+        } else if self.activate_at.map(|at| now < at).unwrap_or(false) {
+            KeyState::Pending
+        } else {
+            KeyState::Active
+        }
+    }
+}
+
+/// Signing keys of access and refresh tokens, addressed by the `kid` of the token
+///
+/// Tokens are signed with the newest active key and verified with any key
+/// which hasn't expired, so keys can be rotated without logging users out.
+pub struct KeyRing {
+    keys: RwLock<Vec<RingKey>>,
+    format: Format,
+}
+
+impl KeyRing {
+    /// Load the ring from the manifest in configuration, or build it from the single
+    /// access key and the refresh secret when no manifest is given
+    pub fn load(config: &KeyConfig, secret: &Secret) -> Result<Self, KeyError> {
+        let mut keys = Vec::new();
+
+        match &config.ring {
+            Some(path) => {
+                let bytes = std::fs::read(path).map_err(|err| KeyError::Io(path.clone(), err))?;
+                let manifest: Manifest = serde_json::from_slice(&bytes).map_err(|err| KeyError::Invalid(err.to_string()))?;
+
+                for (key_use, entries) in [(KeyUse::Access, manifest.access), (KeyUse::Refresh, manifest.refresh)] {
+                    for entry in entries {
+                        keys.push(RingKey::from_entry(key_use, entry)?);
+                    }
+                }
+            }
+            None => {
+                let access = Keys::load(config, Some(&secret.access))?;
+                let kid = access.kid.clone().unwrap_or("access".to_string());
+
+                keys.push(RingKey::new(KeyUse::Access, access.with_kid(kid)));
+                keys.push(RingKey::new(KeyUse::Refresh, Keys::from_secret(Algorithm::HS256, &secret.refresh).with_kid("refresh".to_string())));
+            }
+        }
+
+        for key_use in [KeyUse::Access, KeyUse::Refresh] {
+            if !keys.iter().any(|k| k.key_use == key_use) {
+                return Err(KeyError::Missing(if key_use == KeyUse::Access { "access key in ring" } else { "refresh key in ring" }));
+            }
+        }
+
+        if let Some(k) = keys.iter().find(|k| !config.format.supports(k.keys.algorithm)) {
+            return Err(KeyError::Invalid(format!("{:?} keys can't be used with the {:?} format", k.keys.algorithm, config.format)));
+        }
+
+        let ring = KeyRing { keys: RwLock::new(keys), format: config.format };
+
+        // The ring issues tokens, report a missing private key now rather than on the first request
+        for key_use in [KeyUse::Access, KeyUse::Refresh] {
+            if ring.signing(key_use).is_none() {
+                return Err(KeyError::Missing(if key_use == KeyUse::Access { "active access key with a private key" } else { "active refresh key with a private key" }));
+            }
+        }
+
+        Ok(ring)
+    }
+
+    /// Newest active key, used to sign new tokens
+    pub fn signing(&self, key_use: KeyUse) -> Option<Arc<Keys>> {
+        let now = Utc::now();
+        let keys = self.keys.read().unwrap();
+
+        keys.iter()
+            .filter(|k| k.key_use == key_use && k.state(now) == KeyState::Active && k.keys.encoding().is_some())
+            .max_by_key(|k| k.activate_at)
+            .map(|k| k.keys.clone())
+    }
+
+    /// Key matching the token's `kid`, if it hasn't expired
+    ///
+    /// Tokens issued before the ring was introduced have no `kid` and are checked with the newest active key.
+    pub fn verifying(&self, key_use: KeyUse, kid: Option<&str>) -> Option<Arc<Keys>> {
+        let now = Utc::now();
+        let keys = self.keys.read().unwrap();
+
+        match kid {
+            Some(kid) => keys.iter()
+                .find(|k| k.key_use == key_use && k.keys.kid.as_deref() == Some(kid) && k.state(now) != KeyState::Expired)
+                .map(|k| k.keys.clone()),
+            None => keys.iter()
+                .filter(|k| k.key_use == key_use && k.state(now) == KeyState::Active)
+                .max_by_key(|k| k.activate_at)
+                .map(|k| k.keys.clone()),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Decode a token in the format of the ring, with the ring key it names
+    ///
+    /// The header of the returned data holds the algorithm and id of that key, whatever the format.
+    pub fn decode<T: DeserializeOwned>(&self, token: &str, key_use: KeyUse, validation: &Validation) -> jsonwebtoken::errors::Result<TokenData<T>> {
+        let format = self.format.implementation();
+        let kid = format.kid(token)?;
+
+        let keys = self.verifying(key_use, kid.as_deref())
+            .ok_or(Error::from(ErrorKind::InvalidSignature))?;
+
+        let claims = format.decode(token, &keys, validation)?;
+
+        let mut header = Header::new(keys.algorithm);
+        header.kid = kid;
+
+        Ok(TokenData {
+            header,
+            claims: serde_json::from_value(claims).map_err(|err| Error::from(ErrorKind::Json(Arc::new(err))))?,
+        })
+    }
+
+    /// Sign the claims with the newest active key, in the format of the ring
+    pub fn encode<T: Serialize>(&self, claims: &T, key_use: KeyUse) -> Result<String, KeyError> {
+        let keys = self.signing(key_use).ok_or(KeyError::Missing("active signing key in ring"))?;
+        let claims = serde_json::to_value(claims).map_err(|err| KeyError::Invalid(err.to_string()))?;
+
+        self.format.implementation()
+            .encode(&claims, &keys)
+            .map_err(|err| KeyError::Invalid(err.to_string()))
+    }
+
+    /// Keys consumers should know about: pending, active and retired
+    pub fn published(&self, key_use: KeyUse) -> Vec<Arc<Keys>> {
+        let now = Utc::now();
+        let keys = self.keys.read().unwrap();
+
+        keys.iter()
+            .filter(|k| k.key_use == key_use && k.state(now) != KeyState::Expired)
+            .map(|k| k.keys.clone())
+            .collect()
+    }
+
+    pub fn status(&self) -> Vec<KeyStatus> {
+        let now = Utc::now();
+        let keys = self.keys.read().unwrap();
+
+        keys.iter()
+            .map(|k| KeyStatus {
+                kid: k.keys.kid.clone().unwrap_or_default(),
+                key_use: k.key_use,
+                algorithm: k.keys.algorithm,
+                state: k.state(now),
+            })
+            .collect()
+    }
+
+    /// Move a key to the given state, overriding its schedule
+    ///
+    /// Activating a key retires the keys which were active for the same token type.
+    /// The override is held by this process only: it is lost on restart and other nodes
+    /// keep following the manifest, which should be updated to make it last.
+    pub fn set_state(&self, kid: &str, state: KeyState) -> Result<KeyStatus, KeyError> {
+        let now = Utc::now();
+        let mut keys = self.keys.write().unwrap();
+
+        let index = keys.iter()
+            .position(|k| k.keys.kid.as_deref() == Some(kid))
+            .ok_or(KeyError::Missing("key with this kid"))?;
+
+        let key_use = keys[index].key_use;
+
+        if state == KeyState::Active {
+            if keys[index].keys.encoding().is_none() {
+                return Err(KeyError::Missing("private key to activate this key"));
+            }
+
+            let active: Vec<usize> = keys.iter()
+                .enumerate()
+                .filter(|(i, k)| *i != index && k.key_use == key_use && k.state(now) == KeyState::Active)
+                .map(|(i, _)| i)
+                .collect();
+
+            for i in active {
+                keys[i].forced = Some(KeyState::Retired);
+            }
+        }
+
+        keys[index].forced = Some(state);
+
+        Ok(KeyStatus {
+            kid: kid.to_string(),
+            key_use,
+            algorithm: keys[index].keys.algorithm,
+            state,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(kid: &str) -> KeyEntry {
+        KeyEntry {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS256,
+            private_key: None,
+            public_key: None,
+            secret: Some(format!("secret of {}", kid)),
+            activate_at: None,
+            retire_at: None,
+            expire_at: None,
+        }
+    }
+
+    fn ring(access: Vec<KeyEntry>) -> KeyRing {
+        let mut keys: Vec<RingKey> = access.into_iter().map(|e| RingKey::from_entry(KeyUse::Access, e).unwrap()).collect();
+        keys.push(RingKey::from_entry(KeyUse::Refresh, entry("refresh")).unwrap());
+
+        KeyRing { keys: RwLock::new(keys), format: Format::Jwt }
+    }
+
+    fn signing_kid(ring: &KeyRing) -> Option<String> {
+        ring.signing(KeyUse::Access).and_then(|k| k.kid.clone())
+    }
+
+    #[test]
+    fn follows_the_schedule() {
+        let now = Utc::now();
+        let mut old = entry("old");
+        old.retire_at = Some(now - chrono::Duration::hours(1));
+        let mut current = entry("current");
+        current.activate_at = Some(now - chrono::Duration::hours(1));
+        let mut next = entry("next");
+        next.activate_at = Some(now + chrono::Duration::hours(1));
+        let mut gone = entry("gone");
+        gone.expire_at = Some(now - chrono::Duration::hours(1));
+
+        let ring = ring(vec![old, current, next, gone]);
+
+        assert_eq!(signing_kid(&ring).as_deref(), Some("current"));
+        assert!(ring.verifying(KeyUse::Access, Some("old")).is_some());
+        assert!(ring.verifying(KeyUse::Access, Some("next")).is_some());
+        assert!(ring.verifying(KeyUse::Access, Some("gone")).is_none());
+        assert_eq!(ring.published(KeyUse::Access).len(), 3);
+    }
+
+    #[test]
+    fn activating_retires_the_active_key() {
+        let mut next = entry("next");
+        next.activate_at = Some(Utc::now() + chrono::Duration::hours(1));
+        let ring = ring(vec![entry("current"), next]);
+
+        ring.set_state("next", KeyState::Active).unwrap();
+
+        assert_eq!(signing_kid(&ring).as_deref(), Some("next"));
+        let current = ring.status().into_iter().find(|s| s.kid == "current").unwrap();
+        assert_eq!(current.state, KeyState::Retired);
+        assert!(ring.set_state("unknown", KeyState::Active).is_err());
+    }
+
+    #[test]
+    fn tokens_verify_across_rotation() {
+        #[derive(Serialize, Deserialize)]
+        struct Claims {
+            sub: String,
+            exp: i64,
+        }
+
+        let mut next = entry("next");
+        next.activate_at = Some(Utc::now() + chrono::Duration::hours(1));
+        let ring = ring(vec![entry("current"), next]);
+
+        let claims = Claims { sub: "user".to_string(), exp: Utc::now().timestamp() + 60 };
+        let token = ring.encode(&claims, KeyUse::Access).unwrap();
+
+        ring.set_state("next", KeyState::Active).unwrap();
+
+        let data = ring.decode::<Claims>(&token, KeyUse::Access, &Validation::new(Algorithm::HS256)).unwrap();
+        assert_eq!(data.header.kid.as_deref(), Some("current"));
+
+        ring.set_state("current", KeyState::Expired).unwrap();
+        assert!(ring.decode::<Claims>(&token, KeyUse::Access, &Validation::new(Algorithm::HS256)).is_err());
+    }
+}
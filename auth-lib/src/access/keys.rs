@@ -0,0 +1,339 @@
+use std::fmt;
+
+use jsonwebtoken::{crypto, Algorithm, DecodingKey, EncodingKey};
+use pkcs8::PrivateKeyInfo;
+use serde::Deserialize;
+use spki::{der::{pem, Decode}, ObjectIdentifier, SubjectPublicKeyInfoRef};
+
+use super::tokenize::format::Format;
+
+const RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const EC_PUBLIC_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+const PRIME256V1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+const SECP384R1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.132.0.34");
+const ED25519: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+/// Signing configuration should be parsed in environment
+///
+/// Key files may be PEM or DER. Private keys are PKCS#8 (PKCS#1 is also accepted for RSA),
+/// public keys are SubjectPublicKeyInfo (PKCS#1 is also accepted for RSA).
+#[derive(Debug, Deserialize, Clone)]
+pub struct KeyConfig {
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Algorithm,
+
+    // Format of the issued tokens, jwt or paseto
+    #[serde(default)]
+    pub format: Format,
+
+    // Path to the private key, only needed by the service issuing tokens
+    pub private_key: Option<String>,
+    // Path to the public key, enough to verify tokens
+    pub public_key: Option<String>,
+
+    // Path to a key ring manifest, replacing the single key above when set
+    pub ring: Option<String>,
+
+    // How long consumers may cache the published key set, in seconds
+    #[serde(default = "default_jwks_max_age")]
+    pub jwks_max_age: u64,
+}
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::HS256
+}
+
+fn default_jwks_max_age() -> u64 {
+    3600
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(String, std::io::Error),
+    Missing(&'static str),
+    Invalid(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io(path, err) => write!(f, "Could not read key {}: {}", path, err),
+            KeyError::Missing(what) => write!(f, "Missing {}", what),
+            KeyError::Invalid(reason) => write!(f, "Invalid key: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Public key material as found in the key file
+#[derive(Debug, Clone)]
+pub enum PublicKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ec { curve: Algorithm, x: Vec<u8>, y: Vec<u8> },
+    Ed { x: Vec<u8> },
+}
+
+impl PublicKey {
+    /// Parse PEM or DER encoded SubjectPublicKeyInfo or PKCS#1 RSA public key
+    pub fn parse(bytes: &[u8]) -> Result<Self, KeyError> {
+        let der = der(bytes)?;
+
+        match SubjectPublicKeyInfoRef::from_der(&der) {
+            Ok(spki) => Self::from_spki(spki),
+            Err(_) => Self::from_pkcs1(&der),
+        }
+    }
+
+    fn from_pkcs1(der: &[u8]) -> Result<Self, KeyError> {
+        let key = pkcs1::RsaPublicKey::from_der(der).map_err(|err| KeyError::Invalid(err.to_string()))?;
+
+        Ok(PublicKey::Rsa {
+            n: key.modulus.as_bytes().to_vec(),
+            e: key.public_exponent.as_bytes().to_vec(),
+        })
+    }
+
+    fn from_spki(spki: SubjectPublicKeyInfoRef) -> Result<Self, KeyError> {
+        let key = spki.subject_public_key.raw_bytes();
+
+        match spki.algorithm.oid {
+            RSA_ENCRYPTION => Self::from_pkcs1(key),
+            EC_PUBLIC_KEY => {
+                let curve = match spki.algorithm.parameters_oid() {
+                    Ok(PRIME256V1) => Algorithm::ES256,
+                    Ok(SECP384R1) => Algorithm::ES384,
+                    _ => return Err(KeyError::Invalid("unsupported elliptic curve".to_string())),
+                };
+
+                // Only uncompressed points (0x04 || x || y) are supported
+                if key.first() != Some(&0x04) || key.len() % 2 != 1 {
+                    return Err(KeyError::Invalid("expected an uncompressed EC point".to_string()));
+                }
+                let (x, y) = key[1..].split_at((key.len() - 1) / 2);
+
+                Ok(PublicKey::Ec { curve, x: x.to_vec(), y: y.to_vec() })
+            }
+            ED25519 => Ok(PublicKey::Ed { x: key.to_vec() }),
+            oid => Err(KeyError::Invalid(format!("unsupported key algorithm {}", oid))),
+        }
+    }
+
+    /// Check that the key can be used with the given JWT algorithm
+    pub fn supports(&self, algorithm: Algorithm) -> bool {
+        match self {
+            PublicKey::Rsa { .. } => matches!(
+                algorithm,
+                Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512
+            ),
+            PublicKey::Ec { curve, .. } => *curve == algorithm,
+            PublicKey::Ed { .. } => algorithm == Algorithm::EdDSA,
+        }
+    }
+
+    pub fn decoding_key(&self) -> DecodingKey {
+        match self {
+            PublicKey::Rsa { n, e } => DecodingKey::from_rsa_raw_components(n, e),
+            PublicKey::Ec { x, y, .. } => {
+                let mut point = vec![0x04];
+                point.extend_from_slice(x);
+                point.extend_from_slice(y);
+                DecodingKey::from_ec_der(&point)
+            }
+            PublicKey::Ed { x } => DecodingKey::from_ed_der(x),
+        }
+    }
+}
+
+/// Load a private key for the given algorithm from PEM or DER bytes
+fn encoding_key(algorithm: Algorithm, bytes: &[u8]) -> Result<EncodingKey, KeyError> {
+    let is_pem = bytes.starts_with(b"-----BEGIN");
+
+    let key = match algorithm {
+        Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => {
+            if is_pem { EncodingKey::from_rsa_pem(bytes) } else { Ok(EncodingKey::from_rsa_der(&rsa_pkcs1(bytes))) }
+        }
+        Algorithm::ES256 | Algorithm::ES384 => {
+            if is_pem { EncodingKey::from_ec_pem(bytes) } else { Ok(EncodingKey::from_ec_der(bytes)) }
+        }
+        Algorithm::EdDSA => {
+            if is_pem { EncodingKey::from_ed_pem(bytes) } else { Ok(EncodingKey::from_ed_der(bytes)) }
+        }
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => Ok(EncodingKey::from_secret(bytes)),
+    };
+
+    key.map_err(|err| KeyError::Invalid(err.to_string()))
+}
+
+/// PKCS#1 key wrapped in a PKCS#8 DER key, the signer only reads PKCS#1 DER
+fn rsa_pkcs1(der: &[u8]) -> Vec<u8> {
+    match PrivateKeyInfo::from_der(der) {
+        Ok(info) if info.algorithm.oid == RSA_ENCRYPTION => info.private_key.to_vec(),
+        _ => der.to_vec(),
+    }
+}
+
+/// Sign a probe with the private key and verify it with the public one, so unusable
+/// or mismatched keys are reported when the configuration loads
+fn check_pair(algorithm: Algorithm, encoding: &EncodingKey, decoding: &DecodingKey) -> Result<(), KeyError> {
+    let probe = b"key pair probe";
+    let signature = crypto::sign(probe, encoding, algorithm).map_err(|err| KeyError::Invalid(err.to_string()))?;
+
+    match crypto::verify(&signature, probe, decoding, algorithm) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(KeyError::Invalid("private key doesn't match the public key".to_string())),
+        Err(err) => Err(KeyError::Invalid(err.to_string())),
+    }
+}
+
+/// DER bytes of a PEM or DER key file
+fn der(bytes: &[u8]) -> Result<Vec<u8>, KeyError> {
+    if !bytes.starts_with(b"-----BEGIN") {
+        return Ok(bytes.to_vec());
+    }
+
+    let (_label, der) = pem::decode_vec(bytes).map_err(|err| KeyError::Invalid(err.to_string()))?;
+    Ok(der)
+}
+
+fn read(path: &str) -> Result<Vec<u8>, KeyError> {
+    std::fs::read(path).map_err(|err| KeyError::Io(path.to_string(), err))
+}
+
+/// Keys used to sign and verify access tokens
+///
+/// Services which only verify tokens should be configured with the public key alone.
+#[derive(Clone)]
+pub struct Keys {
+    pub algorithm: Algorithm,
+    pub public: Option<PublicKey>,
+    // Key id sent in the token header, the thumbprint of the public key
+    pub kid: Option<String>,
+
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+    // HMAC secret or DER of the private key, for token formats other than JWT
+    material: Option<Vec<u8>>,
+}
+
+impl Keys {
+    /// Keys for HMAC algorithms, both signing and verifying with the shared secret
+    pub fn from_secret(algorithm: Algorithm, secret: &str) -> Self {
+        Keys {
+            algorithm,
+            public: None,
+            kid: None,
+            encoding: Some(EncodingKey::from_secret(secret.as_bytes())),
+            decoding: DecodingKey::from_secret(secret.as_bytes()),
+            material: Some(secret.as_bytes().to_vec()),
+        }
+    }
+
+    /// Load keys from configuration, the secret is only used by HMAC algorithms
+    pub fn load(config: &KeyConfig, secret: Option<&str>) -> Result<Self, KeyError> {
+        Self::from_parts(config.algorithm, config.private_key.as_deref(), config.public_key.as_deref(), secret)
+    }
+
+    /// Load keys from key file paths, or from the secret for HMAC algorithms
+    pub fn from_parts(algorithm: Algorithm, private_key: Option<&str>, public_key: Option<&str>, secret: Option<&str>) -> Result<Self, KeyError> {
+        if let Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 = algorithm {
+            let secret = secret.ok_or(KeyError::Missing("secret for HMAC signing"))?;
+            return Ok(Self::from_secret(algorithm, secret));
+        }
+
+        let path = public_key.ok_or(KeyError::Missing("public key path"))?;
+        let public = PublicKey::parse(&read(path)?)?;
+
+        if !public.supports(algorithm) {
+            return Err(KeyError::Invalid(format!("public key can't be used with {:?}", algorithm)));
+        }
+
+        let decoding = public.decoding_key();
+
+        let (encoding, material) = match private_key {
+            Some(path) => {
+                let bytes = read(path)?;
+                let encoding = encoding_key(algorithm, &bytes)?;
+                check_pair(algorithm, &encoding, &decoding)?;
+
+                (Some(encoding), Some(der(&bytes)?))
+            }
+            None => (None, None),
+        };
+
+        Ok(Keys {
+            algorithm,
+            decoding,
+            kid: Some(public.thumbprint()),
+            public: Some(public),
+            encoding,
+            material,
+        })
+    }
+
+    /// Replace the key id sent in the token header
+    pub fn with_kid(mut self, kid: String) -> Self {
+        self.kid = Some(kid);
+        self
+    }
+
+    pub fn encoding(&self) -> Option<&EncodingKey> {
+        self.encoding.as_ref()
+    }
+
+    pub fn decoding(&self) -> &DecodingKey {
+        &self.decoding
+    }
+
+    pub(crate) fn material(&self) -> Option<&[u8]> {
+        self.material.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn load(algorithm: Algorithm, private_key: &str, public_key: &str) -> Result<Keys, KeyError> {
+        Keys::from_parts(algorithm, Some(&testdata(private_key)), Some(&testdata(public_key)), None)
+    }
+
+    fn round_trip(keys: &Keys) {
+        let signature = crypto::sign(b"message", keys.encoding().unwrap(), keys.algorithm).unwrap();
+        assert!(crypto::verify(&signature, b"message", keys.decoding(), keys.algorithm).unwrap());
+    }
+
+    #[test]
+    fn loads_rsa_private_keys() {
+        for private_key in ["rsa.pem", "rsa.pkcs1.pem", "rsa.pk8.der"] {
+            let keys = load(Algorithm::RS256, private_key, "rsa.pub.pem").unwrap();
+            round_trip(&keys);
+        }
+    }
+
+    #[test]
+    fn loads_ec_and_ed_keys() {
+        round_trip(&load(Algorithm::ES256, "p256.pem", "p256.pub.pem").unwrap());
+        round_trip(&load(Algorithm::EdDSA, "ed25519.pem", "ed25519.pub.pem").unwrap());
+    }
+
+    #[test]
+    fn rejects_mismatched_keys() {
+        assert!(matches!(load(Algorithm::EdDSA, "ed25519.pem", "other-ed25519.pub.pem"), Err(KeyError::Invalid(_))));
+        assert!(matches!(load(Algorithm::ES384, "p256.pem", "p256.pub.pem"), Err(KeyError::Invalid(_))));
+        assert!(matches!(load(Algorithm::RS256, "p256.pem", "rsa.pub.pem"), Err(KeyError::Invalid(_))));
+    }
+
+    #[test]
+    fn verifies_with_the_public_key_alone() {
+        let keys = Keys::from_parts(Algorithm::EdDSA, None, Some(&testdata("ed25519.pub.pem")), None).unwrap();
+
+        assert!(keys.encoding().is_none());
+        assert!(keys.kid.is_some());
+        assert!(matches!(Keys::from_parts(Algorithm::EdDSA, None, None, None), Err(KeyError::Missing(_))));
+    }
+}
@@ -1,3 +1,12 @@
 pub mod tokens;
+pub mod keys;
+pub mod keyring;
+pub mod jwks;
+pub mod revocation;
+pub mod clients;
+pub mod oidc;
+pub mod exchange;
+pub mod dpop;
+pub mod roles;
 pub mod extractor;
 pub mod tokenize;
\ No newline at end of file
@@ -0,0 +1,121 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::Algorithm;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use crate::db::parser::user::User;
+use super::keyring::{KeyRing, KeyUse};
+
+// Scopes of OpenID Connect, any client of the authorization code flow may request them
+pub const OIDC_SCOPES: [&str; 3] = ["openid", "profile", "email"];
+
+/// Standard claims of a user (OpenID Connect Core section 5.1), only those of the granted scopes are set
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UserClaims {
+    pub sub: String,
+
+    // `profile` scope
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub picture: Option<String>,
+
+    // `email` scope
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+impl UserClaims {
+    pub fn new(user: &User, scopes: &[String]) -> Self {
+        let granted = |scope: &str| scopes.iter().any(|s| s == scope);
+        let mut claims = UserClaims {
+            sub: user.uuid.to_string(),
+            ..Default::default()
+        };
+
+        if granted("profile") {
+            claims.preferred_username = Some(user.username.clone());
+            claims.name = user.name.clone();
+            claims.picture = user.photo_url.clone();
+        }
+        if granted("email") {
+            claims.email = Some(user.email.clone());
+        }
+        claims
+    }
+}
+
+/// Claims of an ID token (OpenID Connect Core section 2), the client is its only audience
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IdClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: usize,
+    pub iat: usize,
+    // When the user signed in
+    pub auth_time: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    // Binds the ID token to the access token issued with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+}
+
+/// Algorithm of ID tokens, which are only issued when the active access key is asymmetric
+///
+/// Clients verify them with the published keys, a shared secret would let them forge one.
+pub fn id_token_algorithm(ring: &KeyRing) -> Option<Algorithm> {
+    ring.signing(KeyUse::Access)
+        .map(|k| k.algorithm)
+        .filter(|alg| !matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512))
+}
+
+/// Left half of the hash of the access token, with the hash function of the signing algorithm
+pub fn at_hash(access_token: &str, algorithm: Algorithm) -> String {
+    let digest = match algorithm {
+        Algorithm::HS384 | Algorithm::RS384 | Algorithm::PS384 | Algorithm::ES384 => Sha384::digest(access_token.as_bytes()).to_vec(),
+        Algorithm::HS512 | Algorithm::RS512 | Algorithm::PS512 | Algorithm::EdDSA => Sha512::digest(access_token.as_bytes()).to_vec(),
+        _ => Sha256::digest(access_token.as_bytes()).to_vec(),
+    };
+
+    URL_SAFE_NO_PAD.encode(&digest[..digest.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::{keys::KeyConfig, tokenize::format::Format, tokens::Secret};
+
+    fn ring(algorithm: Algorithm, key: Option<&str>) -> KeyRing {
+        let path = |file: &str| format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), file);
+
+        let config = KeyConfig {
+            algorithm,
+            format: Format::Jwt,
+            private_key: key.map(|k| path(&format!("{}.pem", k))),
+            public_key: key.map(|k| path(&format!("{}.pub.pem", k))),
+            ring: None,
+            jwks_max_age: 3600,
+        };
+        let secret = Secret {
+            refresh: "refresh".to_string(),
+            access: "access".to_string(),
+            salt: "salt".to_string(),
+            admin: None,
+            hash: "hash".to_string(),
+        };
+
+        KeyRing::load(&config, &secret).unwrap()
+    }
+
+    #[test]
+    fn only_signs_id_tokens_with_asymmetric_keys() {
+        assert_eq!(id_token_algorithm(&ring(Algorithm::HS256, None)), None);
+        assert_eq!(id_token_algorithm(&ring(Algorithm::EdDSA, Some("ed25519"))), Some(Algorithm::EdDSA));
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::HashMap, sync::{mpsc, Arc, Mutex, RwLock}, thread, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Revoked access token, kept until the token would have expired anyway
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Revoked {
+    #[serde(rename = "_id")]
+    pub jti: String,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires: DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub revoked: DateTime<Utc>,
+}
+
+/// Where revoked token ids are kept
+///
+/// Entries are evicted once the token expires, a revoked token can't be valid past that anyway.
+pub trait RevocationStore: Send + Sync {
+    fn revoke(&self, entry: &Revoked);
+    fn is_revoked(&self, jti: &str) -> bool;
+    /// Add the entry unless its id is already present, in a single atomic step
+    ///
+    /// Returns false when it was present, or when that can't be known.
+    fn insert_new(&self, entry: &Revoked) -> bool;
+    /// Entries revoked after the given time, used to notify subscribers of other nodes' revocations
+    fn revoked_since(&self, since: DateTime<Utc>) -> Vec<Revoked>;
+}
+
+/// Which store to use, should be parsed in environment
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    // In-process, for a single node
+    #[default]
+    Memory,
+    // Shared through the database, for several nodes
+    Mongo,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RevocationConfig {
+    #[serde(default)]
+    pub store: StoreKind,
+    // How often a shared store is polled for the revocations of other nodes, in seconds
+    #[serde(default = "default_watch_interval")]
+    pub watch_interval: u64,
+}
+
+fn default_watch_interval() -> u64 {
+    5
+}
+
+impl Default for RevocationConfig {
+    fn default() -> Self {
+        RevocationConfig {
+            store: StoreKind::default(),
+            watch_interval: default_watch_interval(),
+        }
+    }
+}
+
+/// In-process store for single node deployments
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: RwLock<HashMap<String, Revoked>>,
+}
+
+impl RevocationStore for MemoryStore {
+    fn revoke(&self, entry: &Revoked) {
+        let now = Utc::now();
+        let mut entries = self.entries.write().unwrap();
+
+        entries.retain(|_, e| e.expires > now);
+        entries.insert(entry.jti.clone(), entry.clone());
+    }
+
+    fn is_revoked(&self, jti: &str) -> bool {
+        let entries = self.entries.read().unwrap();
+
+        entries.get(jti).map(|e| e.expires > Utc::now()).unwrap_or(false)
+    }
+
+    fn insert_new(&self, entry: &Revoked) -> bool {
+        let now = Utc::now();
+        let mut entries = self.entries.write().unwrap();
+
+        entries.retain(|_, e| e.expires > now);
+        if entries.contains_key(&entry.jti) {
+            return false;
+        }
+        entries.insert(entry.jti.clone(), entry.clone());
+        true
+    }
+
+    fn revoked_since(&self, since: DateTime<Utc>) -> Vec<Revoked> {
+        let entries = self.entries.read().unwrap();
+
+        entries.values().filter(|e| e.revoked > since).cloned().collect()
+    }
+}
+
+fn session_key(sid: &str) -> String {
+    format!("sid:{}", sid)
+}
+
+/// Revocation list checked by the `Token` extractor when registered as `web::Data`
+pub struct Revocations {
+    store: Box<dyn RevocationStore>,
+    subscribers: Mutex<Vec<mpsc::Sender<Revoked>>>,
+}
+
+impl Revocations {
+    pub fn new(store: impl RevocationStore + 'static) -> Self {
+        Revocations {
+            store: Box::new(store),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Revoke the token with the given id until its expiry, as a UTC timestamp
+    pub fn revoke(&self, jti: &str, exp: usize) {
+        let expires = match DateTime::<Utc>::from_timestamp(exp as i64, 0) {
+            Some(e) => e,
+            None => return,
+        };
+
+        if jti.is_empty() || expires <= Utc::now() {
+            return;
+        }
+
+        let entry = Revoked {
+            jti: jti.to_string(),
+            expires,
+            revoked: Utc::now(),
+        };
+
+        self.store.revoke(&entry);
+        self.notify(entry);
+    }
+
+    pub fn is_revoked(&self, jti: &str) -> bool {
+        self.store.is_revoked(jti)
+    }
+
+    /// Revoke every access token issued for the session so far, for the given lifetime of those tokens in seconds
+    ///
+    /// The session is kept in the list under `sid:<sid>`, no token can be issued for it once it ended.
+    pub fn revoke_session(&self, sid: &str, lifetime: i64) {
+        if sid.is_empty() {
+            return;
+        }
+
+        let expires = Utc::now() + chrono::Duration::seconds(lifetime);
+        self.revoke(&session_key(sid), expires.timestamp() as usize);
+    }
+
+    /// Whether the token, or the session it was issued for, was revoked
+    pub fn rejects(&self, jti: &str, sid: &str) -> bool {
+        self.is_revoked(jti) || (!sid.is_empty() && self.is_revoked(&session_key(sid)))
+    }
+
+    /// Record an identifier which may only be used once until the given expiry, e.g. of a client assertion
+    ///
+    /// Returns false when it was already used. Subscribers aren't notified.
+    pub fn use_once(&self, id: &str, exp: usize) -> bool {
+        let expires = match DateTime::<Utc>::from_timestamp(exp as i64, 0) {
+            Some(e) => e,
+            None => return false,
+        };
+
+        self.store.insert_new(&Revoked {
+            jti: id.to_string(),
+            expires,
+            revoked: Utc::now(),
+        })
+    }
+
+    /// Receive every token revoked through this list, and by other nodes when it is watched
+    pub fn subscribe(&self) -> mpsc::Receiver<Revoked> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Poll the store for revocations made by other nodes and notify the subscribers
+    ///
+    /// Only useful with a shared store. Subscribers may see a revocation made by this node twice.
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let revocations = Arc::downgrade(self);
+        let mut since = Utc::now();
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+
+            let revocations = match revocations.upgrade() {
+                Some(r) => r,
+                None => return,
+            };
+
+            for entry in revocations.store.revoked_since(since) {
+                since = since.max(entry.revoked);
+                revocations.notify(entry);
+            }
+        });
+    }
+
+    fn notify(&self, entry: Revoked) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        // Dropped receivers are forgotten
+        subscribers.retain(|s| s.send(entry.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn in_a_minute() -> usize {
+        (Utc::now().timestamp() + 60) as usize
+    }
+
+    #[test]
+    fn revokes_tokens_until_they_expire() {
+        let revocations = Revocations::new(MemoryStore::default());
+
+        revocations.revoke("jti", in_a_minute());
+        revocations.revoke("expired", (Utc::now().timestamp() - 1) as usize);
+
+        assert!(revocations.rejects("jti", "sid"));
+        assert!(!revocations.rejects("other", "sid"));
+        assert!(!revocations.is_revoked("expired"));
+    }
+
+    #[test]
+    fn revokes_every_token_of_a_session() {
+        let revocations = Revocations::new(MemoryStore::default());
+
+        revocations.revoke_session("sid", 60);
+
+        assert!(revocations.rejects("any", "sid"));
+        assert!(!revocations.rejects("any", "other"));
+        assert!(!revocations.rejects("any", ""));
+    }
+
+    #[test]
+    fn uses_an_identifier_once() {
+        let revocations = Revocations::new(MemoryStore::default());
+
+        assert!(revocations.use_once("assertion", in_a_minute()));
+        assert!(!revocations.use_once("assertion", in_a_minute()));
+    }
+
+    #[test]
+    fn uses_an_identifier_once_across_threads() {
+        let revocations = Arc::new(Revocations::new(MemoryStore::default()));
+
+        let used = (0..8)
+            .map(|_| {
+                let revocations = revocations.clone();
+                thread::spawn(move || revocations.use_once("assertion", in_a_minute()))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|used| *used)
+            .count();
+
+        assert_eq!(used, 1);
+    }
+
+    #[test]
+    fn notifies_subscribers() {
+        let revocations = Revocations::new(MemoryStore::default());
+        let receiver = revocations.subscribe();
+
+        revocations.revoke("jti", in_a_minute());
+
+        assert_eq!(receiver.try_recv().unwrap().jti, "jti");
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use serde::{Deserialize, Deserializer, de::Error};
+
+use crate::db::parser::{session::Session, user::User};
+
+/// Role a `RequireRole` guard checks for, declared with the `role!` macro
+pub trait Role {
+    const NAME: &'static str;
+}
+
+/// Declare a marker type for a role, to be used as `RequireRole<Marker>`
+#[macro_export]
+macro_rules! role {
+    ($(#[$meta:meta])* $marker:ident, $name:expr) => {
+        $(#[$meta])*
+        #[derive(Debug)]
+        pub struct $marker;
+
+        impl $crate::access::roles::Role for $marker {
+            const NAME: &'static str = $name;
+        }
+    };
+}
+
+role!(
+    /// Administrators, also accepted by the administration endpoints
+    Admin, "admin"
+);
+role!(Teacher, "teacher");
+role!(Student, "student");
+
+/// Scopes a `RequireScope` guard checks for, all of them are required
+pub trait Scope {
+    const SCOPES: &'static [&'static str];
+}
+
+/// Declare a marker type for one or more scopes, to be used as `RequireScope<Marker>`
+#[macro_export]
+macro_rules! scope {
+    ($(#[$meta:meta])* $marker:ident, $($name:expr),+) => {
+        $(#[$meta])*
+        #[derive(Debug)]
+        pub struct $marker;
+
+        impl $crate::access::roles::Scope for $marker {
+            const SCOPES: &'static [&'static str] = &[$($name),+];
+        }
+    };
+}
+
+/// Roles implied by other roles and the permissions they grant, should be parsed in environment
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RoleConfig {
+    // Hierarchy as `role:implied+implied`, e.g. `admin:teacher,teacher:student`
+    #[serde(default, deserialize_with = "role_lists")]
+    pub hierarchy: HashMap<String, Vec<String>>,
+    // Permissions as `role:permission+permission`, e.g. `teacher:grades:read+grades:write`
+    #[serde(default, deserialize_with = "role_lists")]
+    pub permissions: HashMap<String, Vec<String>>,
+}
+
+/// Entries of `role:name+name`, split on the first colon as permissions contain some
+fn role_lists<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, Vec<String>>, D::Error> {
+    let entries = Vec::<String>::deserialize(deserializer)?;
+    let mut lists: HashMap<String, Vec<String>> = HashMap::new();
+
+    for entry in entries {
+        let (role, names) = match entry.split_once(':') {
+            Some((role, names)) if !role.is_empty() && !names.is_empty() => (role, names),
+            _ => {
+                return Err(D::Error::custom(format!("expected role:name+name, got {}", entry)));
+            }
+        };
+
+        lists.entry(role.to_string()).or_default()
+            .extend(names.split('+').filter(|n| !n.is_empty()).map(|n| n.to_string()));
+    }
+    Ok(lists)
+}
+
+impl RoleConfig {
+    /// The given roles and every role they imply, through any number of levels
+    pub fn effective(&self, roles: &[String]) -> HashSet<String> {
+        let mut effective: HashSet<String> = HashSet::new();
+        let mut pending: Vec<String> = roles.to_vec();
+
+        while let Some(role) = pending.pop() {
+            if let Some(implied) = self.hierarchy.get(&role) {
+                pending.extend(implied.iter().filter(|r| !effective.contains(*r)).cloned());
+            }
+            effective.insert(role);
+        }
+        effective
+    }
+
+    /// Whether the roles are or imply the given one
+    pub fn grants(&self, roles: &[String], role: &str) -> bool {
+        self.effective(roles).contains(role)
+    }
+
+    /// Permissions of the user: those of its roles, including the implied ones, and its own grants
+    pub fn permissions(&self, user: &User) -> BTreeSet<String> {
+        let mut permissions: BTreeSet<String> = user.permissions.iter().cloned().collect();
+
+        for role in self.effective(&user.roles) {
+            permissions.extend(self.permissions.get(&role).into_iter().flatten().cloned());
+        }
+        permissions
+    }
+
+    /// Scope claim of the user's access tokens
+    ///
+    /// First-party sessions carry the user's permissions. OAuth clients keep the scopes they were granted,
+    /// but only the permissions among them which the user holds.
+    pub fn scope(&self, user: &User, session: &Session) -> Option<String> {
+        let permissions = self.permissions(user);
+
+        let scopes: Vec<String> = if session.first_party {
+            permissions.into_iter().collect()
+        } else {
+            session.scope.as_deref().unwrap_or_default().split_whitespace()
+                .filter(|s| !is_permission(s) || permissions.contains(*s))
+                .map(|s| s.to_string())
+                .collect()
+        };
+
+        if scopes.is_empty() {
+            return None;
+        }
+        Some(scopes.join(" "))
+    }
+}
+
+/// Permissions are scopes written as `resource:action`
+pub fn is_permission(scope: &str) -> bool {
+    scope.contains(':')
+}
+
+/// Permission names are `resource:action`, without whitespace nor separators of the configuration
+pub fn valid_permission(permission: &str) -> bool {
+    is_permission(permission) && !permission.contains(['+', ',']) && !permission.contains(char::is_whitespace)
+}
+
+/// Role names may not contain separators of the configuration nor whitespace
+pub fn valid_role(role: &str) -> bool {
+    !role.is_empty() && !role.contains([':', '+', ',']) && !role.contains(char::is_whitespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use crate::db::parser::session::Origin;
+
+    fn config() -> RoleConfig {
+        envy::prefixed("ROLES__").from_iter::<_, RoleConfig>(vec![
+            ("ROLES__HIERARCHY".to_string(), "admin:teacher,teacher:student".to_string()),
+            ("ROLES__PERMISSIONS".to_string(), "teacher:grades:read+grades:write,student:courses:read".to_string()),
+        ]).unwrap()
+    }
+
+    fn user(roles: &[&str]) -> User {
+        User {
+            uuid: Uuid::new_v4(),
+            username: String::from("user"),
+            email: String::from("user@example.com"),
+            password_hash: String::new(),
+            name: None,
+            refresh_token: None,
+            refresh_hash: None,
+            photo_url: None,
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            permissions: vec![String::from("reports:read")],
+            refresh_creation: Utc::now(),
+        }
+    }
+
+    fn session(first_party: bool, scope: Option<&str>) -> Session {
+        let mut session = Session::new(Uuid::new_v4(), Uuid::new_v4(), Origin::default(), Some(String::from("client")), scope.map(|s| s.to_string()), String::new(), Utc::now());
+        session.first_party = first_party;
+        session
+    }
+
+    #[test]
+    fn parses_lists_split_on_the_first_colon() {
+        let config = config();
+
+        assert_eq!(config.hierarchy["admin"], vec!["teacher"]);
+        assert_eq!(config.permissions["teacher"], vec!["grades:read", "grades:write"]);
+    }
+
+    #[test]
+    fn refuses_entries_without_a_role() {
+        let config = envy::prefixed("ROLES__").from_iter::<_, RoleConfig>(vec![
+            ("ROLES__HIERARCHY".to_string(), ":student".to_string()),
+        ]);
+
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn grants_implied_roles_through_every_level() {
+        let config = config();
+        let roles = vec![String::from("admin")];
+
+        assert!(config.grants(&roles, "admin"));
+        assert!(config.grants(&roles, "student"));
+        assert!(!config.grants(&[String::from("student")], "teacher"));
+    }
+
+    #[test]
+    fn stops_on_cyclic_hierarchies() {
+        let config = envy::prefixed("ROLES__").from_iter::<_, RoleConfig>(vec![
+            ("ROLES__HIERARCHY".to_string(), "a:b,b:a".to_string()),
+        ]).unwrap();
+
+        assert_eq!(config.effective(&[String::from("a")]).len(), 2);
+    }
+
+    #[test]
+    fn first_party_sessions_carry_every_permission() {
+        let scope = config().scope(&user(&["teacher"]), &session(true, None));
+
+        assert_eq!(scope.as_deref(), Some("courses:read grades:read grades:write reports:read"));
+    }
+
+    #[test]
+    fn clients_keep_granted_scopes_the_user_holds() {
+        let config = config();
+        let granted = session(false, Some("openid grades:write admin:write"));
+
+        assert_eq!(config.scope(&user(&["teacher"]), &granted).as_deref(), Some("openid grades:write"));
+        assert_eq!(config.scope(&user(&["student"]), &granted).as_deref(), Some("openid"));
+    }
+
+    #[test]
+    fn clients_without_granted_scopes_get_no_permissions() {
+        let scope = config().scope(&user(&["admin"]), &session(false, Some("")));
+
+        assert_eq!(scope, None);
+    }
+}
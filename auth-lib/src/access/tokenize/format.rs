@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use jsonwebtoken::{decode, decode_header, encode, errors::{Error, ErrorKind, Result}, Algorithm, Header, TokenData, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::access::keys::Keys;
+use super::paseto::PasetoV4;
+
+/// Serialization and protection of token claims
+///
+/// Implementations check the registered claims like jsonwebtoken does, so the extractors don't depend on the format.
+pub trait TokenFormat: Send + Sync {
+    /// Seal the claims with the key
+    fn encode(&self, claims: &Value, keys: &Keys) -> Result<String>;
+
+    /// Id of the key named by the token, read before it is verified to find that key
+    fn kid(&self, token: &str) -> Result<Option<String>>;
+
+    /// Verify the token with the key and check its registered claims
+    fn decode(&self, token: &str, keys: &Keys, validation: &Validation) -> Result<Value>;
+}
+
+/// JSON Web Tokens, the key id is sent in the header
+pub struct Jwt;
+
+impl TokenFormat for Jwt {
+    fn encode(&self, claims: &Value, keys: &Keys) -> Result<String> {
+        let mut header = Header::new(keys.algorithm);
+        header.kid = keys.kid.clone();
+
+        encode(&header, claims, keys.encoding().ok_or(ErrorKind::InvalidKeyFormat)?)
+    }
+
+    fn kid(&self, token: &str) -> Result<Option<String>> {
+        Ok(decode_header(token)?.kid)
+    }
+
+    fn decode(&self, token: &str, keys: &Keys, validation: &Validation) -> Result<Value> {
+        // Only the algorithm of the key may be used, whatever the header claims
+        let mut validation = validation.clone();
+        validation.algorithms = vec![keys.algorithm];
+
+        Ok(decode::<Value>(token, keys.decoding(), &validation)?.claims)
+    }
+}
+
+/// Format of the tokens of a deployment
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Jwt,
+    // PASETO v4, `v4.public` with EdDSA keys and `v4.local` with HMAC secrets
+    Paseto,
+}
+
+impl Format {
+    pub fn implementation(&self) -> &'static dyn TokenFormat {
+        match self {
+            Format::Jwt => &Jwt,
+            Format::Paseto => &PasetoV4,
+        }
+    }
+
+    /// Decode a token of this format with a single key, for services without a key ring
+    pub fn decode<T: DeserializeOwned>(&self, token: &str, keys: &Keys, validation: &Validation) -> Result<TokenData<T>> {
+        let claims = self.implementation().decode(token, keys, validation)?;
+
+        Ok(TokenData {
+            header: Header::new(keys.algorithm),
+            claims: serde_json::from_value(claims).map_err(|err| Error::from(ErrorKind::Json(Arc::new(err))))?,
+        })
+    }
+
+    /// Whether keys of the algorithm can protect tokens of this format
+    pub fn supports(&self, algorithm: Algorithm) -> bool {
+        match self {
+            Format::Jwt => true,
+            Format::Paseto => matches!(algorithm, Algorithm::EdDSA | Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims() -> Value {
+        serde_json::json!({ "sub": "user", "iss": "auth-service", "exp": chrono::Utc::now().timestamp() + 60 })
+    }
+
+    fn validation() -> Validation {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&["auth-service"]);
+        validation
+    }
+
+    #[test]
+    fn decodes_with_a_single_key_in_either_format() {
+        let keys = Keys::from_secret(Algorithm::HS256, "secret");
+
+        for format in [Format::Jwt, Format::Paseto] {
+            let token = format.implementation().encode(&claims(), &keys).unwrap();
+            let data = format.decode::<Value>(&token, &keys, &validation()).unwrap();
+
+            assert_eq!(data.claims["sub"], "user");
+        }
+    }
+
+    #[test]
+    fn refuses_tokens_of_the_other_format() {
+        let keys = Keys::from_secret(Algorithm::HS256, "secret");
+
+        let jwt = Format::Jwt.implementation().encode(&claims(), &keys).unwrap();
+        let paseto = Format::Paseto.implementation().encode(&claims(), &keys).unwrap();
+
+        assert!(Format::Paseto.decode::<Value>(&jwt, &keys, &validation()).is_err());
+        assert!(Format::Jwt.decode::<Value>(&paseto, &keys, &validation()).is_err());
+    }
+}
@@ -1 +1,3 @@
-pub mod parser;
\ No newline at end of file
+pub mod format;
+pub mod parser;
+pub mod paseto;
\ No newline at end of file
@@ -1,43 +1,137 @@
-use actix_web::web;
 use chrono::{Utc, Duration};
-use jsonwebtoken::{ encode, EncodingKey, Header};
-use crate::access::{extractor::extract::{AccessClaims, Token, RefreshClaims, Info}, tokens::Secret};
+use jsonwebtoken::{ encode, Header};
+use uuid::Uuid;
+use crate::{db::parser::{session::Session, user::User}, access::{dpop::Confirmation, extractor::extract::{AccessClaims, Actor, Profile, RefreshClaims, Info, TokenKind}, keyring::{KeyRing, KeyUse}, keys::KeyError, roles::RoleConfig, oidc::{at_hash, IdClaims}, tokens::TokenConfig}};
 
-/// Encode JWT access token based on username, name and secret from app's shared data
-pub fn encode_access_token(username: String, name: String, secret: &web::Data<Secret>) -> String {
+/// Encode the access token based on the user, its session, lifetime in seconds, the permissions of its roles and the key ring from app's shared data
+pub fn encode_access_token(user: &User, session: &Session, lifetime: i64, roles: &RoleConfig, ring: &KeyRing, config: &TokenConfig) -> Result<String, KeyError> {
     // Should expire in a short time
-    let exp: usize = (Utc::now() + Duration::minutes(5)).timestamp() as usize;
+    let now = Utc::now();
+    let exp: usize = (now + Duration::seconds(lifetime)).timestamp() as usize;
 
     let claims = AccessClaims {
+        iss: config.issuer.clone(),
+        aud: config.audience.clone(),
+        sub: user.uuid.to_string(),
+        iat: now.timestamp() as usize,
+        nbf: now.timestamp() as usize,
+        jti: Uuid::new_v4().to_string(),
+        sid: session.uuid.to_string(),
         exp,
-        custom: Token {
-            username: username.clone(),
-            name: name.clone(),
-        }
+        kind: TokenKind::User,
+        // First-party sessions name a client type, not a registered client
+        client_id: if session.first_party { None } else { session.client.clone() },
+        scope: roles.scope(user, session),
+        act: None,
+        cnf: session.jkt.clone().map(|jkt| Confirmation { jkt }),
+        // Roles are for the service's own applications, OAuth clients only get the scopes they were granted
+        roles: if session.first_party { user.roles.clone() } else { Vec::new() },
+        custom: Profile {
+            username: user.username.clone(),
+            name: user.name.clone().unwrap_or_default(),
+        },
+    };
+
+    ring.encode(&claims, KeyUse::Access)
+}
+
+/// Encode the access token of a service client, with its granted scopes and audiences, DPoP key, lifetime in seconds and the key ring from app's shared data
+pub fn encode_service_token(client_id: &str, scope: Option<String>, audience: Vec<String>, jkt: Option<String>, lifetime: i64, ring: &KeyRing, config: &TokenConfig) -> Result<String, KeyError> {
+    let now = Utc::now();
+    let exp: usize = (now + Duration::seconds(lifetime)).timestamp() as usize;
+
+    // The client is the subject, there is no user nor session
+    let claims = AccessClaims {
+        iss: config.issuer.clone(),
+        aud: audience,
+        sub: client_id.to_string(),
+        iat: now.timestamp() as usize,
+        nbf: now.timestamp() as usize,
+        jti: Uuid::new_v4().to_string(),
+        sid: String::new(),
+        exp,
+        kind: TokenKind::Service,
+        client_id: Some(client_id.to_string()),
+        scope,
+        act: None,
+        cnf: jkt.map(|jkt| Confirmation { jkt }),
+        roles: Vec::new(),
+        custom: Profile::default(),
+    };
+
+    ring.encode(&claims, KeyUse::Access)
+}
+
+/// Encode the token exchanged for the subject token, narrowed to the audience and scopes, with the client as its actor
+///
+/// The token is bound to the key in the subject's `cnf`, which the caller replaces with the actor's.
+pub fn encode_exchanged_token(subject: AccessClaims, actor: &str, audience: String, scope: Option<String>, exp: usize, ring: &KeyRing, config: &TokenConfig) -> Result<String, KeyError> {
+    let now = Utc::now();
+
+    // The subject, its kind and profile are kept, the previous actor is nested under the new one
+    let claims = AccessClaims {
+        iss: config.issuer.clone(),
+        aud: vec![audience],
+        sub: subject.sub,
+        iat: now.timestamp() as usize,
+        nbf: now.timestamp() as usize,
+        jti: Uuid::new_v4().to_string(),
+        sid: subject.sid,
+        exp,
+        kind: subject.kind,
+        client_id: Some(actor.to_string()),
+        scope,
+        act: Some(Actor {
+            sub: actor.to_string(),
+            act: subject.act.map(Box::new),
+        }),
+        cnf: subject.cnf,
+        // Roles stay with the user's own tokens, the actor only gets the scopes of the exchange
+        roles: Vec::new(),
+        custom: subject.custom,
     };
 
+    ring.encode(&claims, KeyUse::Access)
+}
+
+/// Encode the ID token of a user signing in to an OAuth client, bound to the access token issued with it
+///
+/// ID tokens are always JWT, as OpenID Connect requires, whatever the format of the ring.
+pub fn encode_id_token(mut claims: IdClaims, access_token: &str, ring: &KeyRing) -> Result<String, KeyError> {
+    let keys = ring.signing(KeyUse::Access).ok_or(KeyError::Missing("active signing key in ring"))?;
+
+    claims.at_hash = Some(at_hash(access_token, keys.algorithm));
+
+    let mut header = Header::new(keys.algorithm);
+    header.kid = keys.kid.clone();
+
     encode(
-        &Header::default(),
+        &header,
         &claims,
-        &EncodingKey::from_secret(secret.access.as_str().as_ref())
-    ).unwrap()
+        keys.encoding().ok_or(KeyError::Missing("private key"))?
+    ).map_err(|err| KeyError::Invalid(err.to_string()))
 }
 
-/// Encode JWT access token based on username and secret from app's shared data
-pub fn encode_refresh_token(username: String, secret: &web::Data<Secret>) -> String {
+/// Encode the refresh token based on user's UUID, username, session id, lifetime in seconds and the key ring from app's shared data
+pub fn encode_refresh_token(uuid: &Uuid, username: String, session: &str, lifetime: i64, ring: &KeyRing, config: &TokenConfig) -> Result<String, KeyError> {
     // Should take longer than the access token to expire
-    let exp: usize = (Utc::now() + Duration::days(7)).timestamp() as usize;
+    let now = Utc::now();
+    let exp: usize = (now + Duration::seconds(lifetime)).timestamp() as usize;
 
+    // Refresh tokens are only ever presented back to the issuer
     let claims = RefreshClaims {
+        iss: config.issuer.clone(),
+        aud: vec![config.issuer.clone()],
+        sub: uuid.to_string(),
+        iat: now.timestamp() as usize,
+        nbf: now.timestamp() as usize,
+        jti: Uuid::new_v4().to_string(),
+        sid: session.to_string(),
         exp,
         id: Info {
             username: username.clone()
         }
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.refresh.as_str().as_ref())
-    ).unwrap()
+    ring.encode(&claims, KeyUse::Refresh)
 }
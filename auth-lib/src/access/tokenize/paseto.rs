@@ -0,0 +1,350 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use blake2b_simd::Params;
+use chacha20::{cipher::{KeyIvInit, StreamCipher}, XChaCha20};
+use chrono::{DateTime, SecondsFormat, Utc};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{errors::{Error, ErrorKind, Result}, Algorithm, Validation};
+use rand::{rngs::OsRng, RngCore};
+use ring::signature::{Ed25519KeyPair, UnparsedPublicKey, ED25519};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::access::keys::{Keys, PublicKey};
+use super::format::TokenFormat;
+
+const PUBLIC_HEADER: &str = "v4.public.";
+const LOCAL_HEADER: &str = "v4.local.";
+
+// Registered claims carried as RFC 3339 date-times instead of timestamps
+const TIME_CLAIMS: [&str; 3] = ["exp", "nbf", "iat"];
+
+/// PASETO version 4: `v4.public` tokens for Ed25519 keys, `v4.local` tokens for HMAC secrets
+///
+/// The purpose follows from the key, never from the token, so a token can't choose how it is verified.
+/// The key id is sent in the footer as `{"kid": "..."}`.
+pub struct PasetoV4;
+
+fn invalid() -> Error {
+    ErrorKind::InvalidToken.into()
+}
+
+/// Pre-authentication encoding of the pieces covered by the signature or tag
+fn pae(pieces: &[&[u8]]) -> Vec<u8> {
+    // Lengths are little endian 64 bit integers with the top bit cleared
+    let le64 = |n: usize| ((n as u64) & (u64::MAX >> 1)).to_le_bytes();
+
+    let mut out = le64(pieces.len()).to_vec();
+    for piece in pieces {
+        out.extend_from_slice(&le64(piece.len()));
+        out.extend_from_slice(piece);
+    }
+    out
+}
+
+/// XChaCha20 with a 24 byte nonce, as libsodium's crypto_stream_xchacha20_xor
+fn xchacha20(key: &[u8; 32], nonce: &[u8; 24], data: &mut [u8]) {
+    XChaCha20::new(key.into(), nonce.into()).apply_keystream(data);
+}
+
+fn blake2b(key: &[u8], length: usize, pieces: &[&[u8]]) -> Vec<u8> {
+    let mut state = Params::new().hash_length(length).key(key).to_state();
+    for piece in pieces {
+        state.update(piece);
+    }
+    state.finalize().as_bytes().to_vec()
+}
+
+/// Encryption key, nonce and authentication key of a `v4.local` token
+fn local_keys(key: &[u8; 32], nonce: &[u8]) -> ([u8; 32], [u8; 24], Vec<u8>) {
+    let tmp = blake2b(key, 56, &[b"paseto-encryption-key", nonce]);
+    let auth_key = blake2b(key, 32, &[b"paseto-auth-key-for-aead", nonce]);
+
+    let mut encryption_key = [0u8; 32];
+    let mut counter_nonce = [0u8; 24];
+    encryption_key.copy_from_slice(&tmp[..32]);
+    counter_nonce.copy_from_slice(&tmp[32..]);
+
+    (encryption_key, counter_nonce, auth_key)
+}
+
+/// Encrypt the message into a `v4.local` token with the given nonce
+pub fn encrypt(key: &[u8; 32], message: &[u8], footer: &[u8], nonce: &[u8; 32]) -> String {
+    let (encryption_key, counter_nonce, auth_key) = local_keys(key, nonce);
+
+    let mut ciphertext = message.to_vec();
+    xchacha20(&encryption_key, &counter_nonce, &mut ciphertext);
+
+    let tag = blake2b(&auth_key, 32, &[&pae(&[LOCAL_HEADER.as_bytes(), nonce, &ciphertext, footer, b""])]);
+
+    let body = [nonce.as_slice(), &ciphertext, &tag].concat();
+    seal(LOCAL_HEADER, &body, footer)
+}
+
+/// Decrypt a `v4.local` token, once its tag is verified
+pub fn decrypt(key: &[u8; 32], token: &str) -> Result<Vec<u8>> {
+    let (body, footer) = open(LOCAL_HEADER, token)?;
+
+    if body.len() < 64 {
+        return Err(invalid());
+    }
+    let (nonce, rest) = body.split_at(32);
+    let (ciphertext, tag) = rest.split_at(rest.len() - 32);
+
+    let (encryption_key, counter_nonce, auth_key) = local_keys(key, nonce);
+    let expected = blake2b(&auth_key, 32, &[&pae(&[LOCAL_HEADER.as_bytes(), nonce, ciphertext, &footer, b""])]);
+
+    if !bool::from(expected.ct_eq(tag)) {
+        return Err(ErrorKind::InvalidSignature.into());
+    }
+
+    let mut message = ciphertext.to_vec();
+    xchacha20(&encryption_key, &counter_nonce, &mut message);
+    Ok(message)
+}
+
+/// Sign the message into a `v4.public` token with a PKCS#8 Ed25519 private key
+pub fn sign(private_key: &[u8], message: &[u8], footer: &[u8]) -> Result<String> {
+    let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(private_key).map_err(|_| Error::from(ErrorKind::InvalidKeyFormat))?;
+    let signature = pair.sign(&pae(&[PUBLIC_HEADER.as_bytes(), message, footer, b""]));
+
+    let body = [message, signature.as_ref()].concat();
+    Ok(seal(PUBLIC_HEADER, &body, footer))
+}
+
+/// Verify a `v4.public` token with the raw Ed25519 public key and return its message
+pub fn verify(public_key: &[u8], token: &str) -> Result<Vec<u8>> {
+    let (body, footer) = open(PUBLIC_HEADER, token)?;
+
+    if body.len() < 64 {
+        return Err(invalid());
+    }
+    let (message, signature) = body.split_at(body.len() - 64);
+
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(&pae(&[PUBLIC_HEADER.as_bytes(), message, &footer, b""]), signature)
+        .map_err(|_| Error::from(ErrorKind::InvalidSignature))?;
+
+    Ok(message.to_vec())
+}
+
+fn seal(header: &str, body: &[u8], footer: &[u8]) -> String {
+    let mut token = format!("{}{}", header, URL_SAFE_NO_PAD.encode(body));
+
+    if !footer.is_empty() {
+        token.push('.');
+        token.push_str(&URL_SAFE_NO_PAD.encode(footer));
+    }
+    token
+}
+
+/// Body and footer of a token with the given header
+fn open(header: &str, token: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    let rest = token.strip_prefix(header).ok_or_else(invalid)?;
+
+    let (body, footer) = match rest.split_once('.') {
+        Some((body, footer)) => (body, URL_SAFE_NO_PAD.decode(footer).map_err(|_| invalid())?),
+        None => (rest, Vec::new()),
+    };
+
+    Ok((URL_SAFE_NO_PAD.decode(body).map_err(|_| invalid())?, footer))
+}
+
+/// Footer of a token, not verified yet
+fn footer(token: &str) -> Option<Value> {
+    let encoded = token.splitn(4, '.').nth(3)?;
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(encoded).ok()?).ok()
+}
+
+/// Symmetric key of `v4.local` tokens, derived from the HMAC secret of the key
+fn local_key(keys: &Keys) -> Result<[u8; 32]> {
+    let secret = keys.material().ok_or(Error::from(ErrorKind::InvalidKeyFormat))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(b"paseto-v4-local");
+
+    Ok(mac.finalize().into_bytes().into())
+}
+
+/// Timestamps of the registered claims become date-times, as PASETO expects
+fn to_date_times(claims: &mut Map<String, Value>) {
+    for name in TIME_CLAIMS {
+        let date = claims.get(name)
+            .and_then(|v| v.as_i64())
+            .and_then(|t| DateTime::<Utc>::from_timestamp(t, 0))
+            .map(|d| d.to_rfc3339_opts(SecondsFormat::Secs, true));
+
+        if let Some(date) = date {
+            claims.insert(name.to_string(), Value::String(date));
+        }
+    }
+}
+
+/// Date-times of the registered claims become timestamps again, for the claims structs
+fn to_timestamps(claims: &mut Map<String, Value>) -> Result<()> {
+    for name in TIME_CLAIMS {
+        if let Some(Value::String(date)) = claims.get(name) {
+            let timestamp = DateTime::parse_from_rfc3339(date).map_err(|_| invalid())?.timestamp();
+            claims.insert(name.to_string(), Value::from(timestamp));
+        }
+    }
+    Ok(())
+}
+
+/// Check the registered claims with the same rules as jsonwebtoken
+fn validate(claims: &Map<String, Value>, validation: &Validation) -> Result<()> {
+    let now = Utc::now().timestamp() as u64;
+    let number = |name: &str| claims.get(name).and_then(|v| v.as_u64());
+    let strings = |name: &str| -> Option<Vec<String>> {
+        match claims.get(name)? {
+            Value::String(s) => Some(vec![s.clone()]),
+            Value::Array(values) => Some(values.iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect()),
+            _ => None,
+        }
+    };
+
+    for required in &validation.required_spec_claims {
+        let present = match required.as_str() {
+            "exp" | "nbf" => number(required).is_some(),
+            "sub" => claims.get("sub").and_then(|v| v.as_str()).is_some(),
+            "iss" | "aud" => strings(required).is_some(),
+            _ => continue,
+        };
+
+        if !present {
+            return Err(ErrorKind::MissingRequiredClaim(required.clone()).into());
+        }
+    }
+
+    if matches!(number("exp"), Some(exp) if validation.validate_exp && exp < now - validation.leeway) {
+        return Err(ErrorKind::ExpiredSignature.into());
+    }
+    if matches!(number("nbf"), Some(nbf) if validation.validate_nbf && nbf > now + validation.leeway) {
+        return Err(ErrorKind::ImmatureSignature.into());
+    }
+
+    if let (Some(sub), Some(expected)) = (claims.get("sub").and_then(|v| v.as_str()), validation.sub.as_deref()) {
+        if sub != expected {
+            return Err(ErrorKind::InvalidSubject.into());
+        }
+    }
+    if let (Some(iss), Some(expected)) = (strings("iss"), validation.iss.as_ref()) {
+        if !iss.iter().any(|i| expected.contains(i)) {
+            return Err(ErrorKind::InvalidIssuer.into());
+        }
+    }
+    if let (Some(aud), Some(expected)) = (strings("aud"), validation.aud.as_ref()) {
+        if !aud.iter().any(|a| expected.contains(a)) {
+            return Err(ErrorKind::InvalidAudience.into());
+        }
+    }
+    Ok(())
+}
+
+impl TokenFormat for PasetoV4 {
+    fn encode(&self, claims: &Value, keys: &Keys) -> Result<String> {
+        let mut claims = claims.as_object().cloned().ok_or_else(invalid)?;
+        to_date_times(&mut claims);
+
+        let message = serde_json::to_vec(&claims).map_err(|_| invalid())?;
+        let footer = keys.kid.as_ref().map(|kid| serde_json::json!({ "kid": kid }).to_string()).unwrap_or_default();
+
+        match keys.algorithm {
+            Algorithm::EdDSA => sign(keys.material().ok_or(Error::from(ErrorKind::InvalidKeyFormat))?, &message, footer.as_bytes()),
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let mut nonce = [0u8; 32];
+                OsRng.fill_bytes(&mut nonce);
+
+                Ok(encrypt(&local_key(keys)?, &message, footer.as_bytes(), &nonce))
+            }
+            _ => Err(ErrorKind::InvalidAlgorithm.into()),
+        }
+    }
+
+    fn kid(&self, token: &str) -> Result<Option<String>> {
+        if !token.starts_with(PUBLIC_HEADER) && !token.starts_with(LOCAL_HEADER) {
+            return Err(invalid());
+        }
+        Ok(footer(token).and_then(|f| f.get("kid")?.as_str().map(|kid| kid.to_string())))
+    }
+
+    fn decode(&self, token: &str, keys: &Keys, validation: &Validation) -> Result<Value> {
+        let message = match (keys.algorithm, &keys.public) {
+            (Algorithm::EdDSA, Some(PublicKey::Ed { x })) => verify(x, token)?,
+            (Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512, _) => decrypt(&local_key(keys)?, token)?,
+            _ => return Err(ErrorKind::InvalidAlgorithm.into()),
+        };
+
+        let mut claims: Map<String, Value> = serde_json::from_slice(&message).map_err(|_| invalid())?;
+        to_timestamps(&mut claims)?;
+        validate(&claims, validation)?;
+
+        Ok(Value::Object(claims))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    // Test vectors 4-E-1 and 4-S-1 of the PASETO specification
+    const LOCAL_KEY: &str = "707172737475767778797a7b7c7d7e7f808182838485868788898a8b8c8d8e8f";
+    const LOCAL_TOKEN: &str = "v4.local.AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAr68PS4AXe7If_ZgesdkUMvSwscFlAl1pk5HC0e8kApeaqMfGo_7OpBnwJOAbY9V7WU6abu74MmcUE8YWAiaArVI8XJ5hOb_4v9RmDkneN0S92dx0OW4pgy7omxgf3S8c3LlQg";
+    const LOCAL_PAYLOAD: &str = r#"{"data":"this is a secret message","exp":"2022-01-01T00:00:00+00:00"}"#;
+
+    const SECRET_SEED: &str = "b4cbfb43df4ce210727d953e4a713307fa19bb7d9f85041438d9e11b942a3774";
+    const PUBLIC_KEY: &str = "1eb9dbbbbc047c03fd70604e0071f0987e16b28b757225c11f00415d0e20b1a2";
+    const PUBLIC_TOKEN: &str = "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMVQwMDowMDowMCswMDowMCJ9bg_XBBzds8lTZShVlwwKSgeKpLT3yukTw6JUz3W4h_ExsQV-P0V54zemZDcAxFaSeef1QlXEFtkqxT1ciiQEDA";
+    const PUBLIC_PAYLOAD: &str = r#"{"data":"this is a signed message","exp":"2022-01-01T00:00:00+00:00"}"#;
+
+    fn local_key() -> [u8; 32] {
+        hex(LOCAL_KEY).try_into().unwrap()
+    }
+
+    /// PKCS#8 v1 document of an Ed25519 seed
+    fn pkcs8(seed: &[u8]) -> Vec<u8> {
+        [hex("302e020100300506032b657004220420"), seed.to_vec()].concat()
+    }
+
+    #[test]
+    fn encrypts_the_local_vector() {
+        assert_eq!(encrypt(&local_key(), LOCAL_PAYLOAD.as_bytes(), b"", &[0u8; 32]), LOCAL_TOKEN);
+        assert_eq!(decrypt(&local_key(), LOCAL_TOKEN).unwrap(), LOCAL_PAYLOAD.as_bytes());
+    }
+
+    #[test]
+    fn signs_the_public_vector() {
+        assert_eq!(sign(&pkcs8(&hex(SECRET_SEED)), PUBLIC_PAYLOAD.as_bytes(), b"").unwrap(), PUBLIC_TOKEN);
+        assert_eq!(verify(&hex(PUBLIC_KEY), PUBLIC_TOKEN).unwrap(), PUBLIC_PAYLOAD.as_bytes());
+    }
+
+    #[test]
+    fn round_trips_with_a_footer() {
+        let token = encrypt(&local_key(), b"message", br#"{"kid":"local"}"#, &[7u8; 32]);
+        assert_eq!(decrypt(&local_key(), &token).unwrap(), b"message");
+
+        let token = sign(&pkcs8(&hex(SECRET_SEED)), b"message", br#"{"kid":"public"}"#).unwrap();
+        assert_eq!(verify(&hex(PUBLIC_KEY), &token).unwrap(), b"message");
+    }
+
+    #[test]
+    fn refuses_tampered_tokens() {
+        let mut other = local_key();
+        other[0] ^= 1;
+        assert!(decrypt(&other, LOCAL_TOKEN).is_err());
+
+        // The footer is authenticated as well
+        assert!(decrypt(&local_key(), &format!("{}.e30", LOCAL_TOKEN)).is_err());
+        assert!(verify(&hex(PUBLIC_KEY), &format!("{}.e30", PUBLIC_TOKEN)).is_err());
+
+        let tampered = PUBLIC_TOKEN.replacen("eyJkYXRh", "eyJkYXRi", 1);
+        assert!(verify(&hex(PUBLIC_KEY), &tampered).is_err());
+
+        // The purpose follows from the function, never from the token
+        assert!(decrypt(&local_key(), PUBLIC_TOKEN).is_err());
+    }
+}
@@ -1,4 +1,11 @@
-use serde::Deserialize;
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use jsonwebtoken::Validation;
+use serde::{Deserialize, Deserializer, de::Error};
+use sha2::{Digest, Sha256};
 
 /// Secret should parsed in environment
 #[derive(Debug, Deserialize, Clone)]
@@ -6,4 +13,270 @@ pub struct Secret {
     pub refresh: String,
     pub access: String,
     pub salt: String,
-}
\ No newline at end of file
+    // Key for the administration endpoints, which are disabled without it
+    pub admin: Option<String>,
+    // Key of the refresh token hashes stored in the database, distinct from the signing secrets
+    pub hash: String,
+}
+
+/// Keyed hash of refresh tokens, so the database never holds a usable token
+#[derive(Clone)]
+pub struct TokenHasher {
+    key: Vec<u8>,
+}
+
+impl TokenHasher {
+    pub fn new(secret: &Secret) -> Self {
+        TokenHasher {
+            key: secret.hash.as_bytes().to_vec(),
+        }
+    }
+
+    /// HMAC-SHA256 of the token
+    pub fn hash(&self, token: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(token.as_bytes());
+
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    /// Unkeyed SHA-256 stored by sessions created before the hashes were keyed
+    pub fn legacy_hash(token: &str) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+    }
+
+    /// Every hash a stored token may have been saved under
+    pub fn candidates(&self, token: &str) -> Vec<String> {
+        vec![self.hash(token), Self::legacy_hash(token)]
+    }
+}
+
+/// Random URL safe string with 256 bits of entropy, e.g. for authorization codes
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Registered claims of issued tokens and their validation, should be parsed in environment
+#[derive(Debug, Deserialize, Clone)]
+pub struct TokenConfig {
+    #[serde(default = "default_issuer")]
+    pub issuer: String,
+    // Audiences of access tokens, not checked when empty
+    #[serde(default)]
+    pub audience: Vec<String>,
+    // Allowed clock skew in seconds for `exp` and `nbf`
+    #[serde(default = "default_leeway")]
+    pub leeway: u64,
+    // Claims which must be present in a token: any of exp, nbf, iss, aud and sub, iss and the configured aud always are
+    #[serde(default = "default_required_claims")]
+    pub required_claims: Vec<String>,
+
+    // Lifetimes of issued tokens in seconds
+    #[serde(default = "default_access_lifetime")]
+    pub access_lifetime: i64,
+    #[serde(default = "default_refresh_lifetime")]
+    pub refresh_lifetime: i64,
+    // Overrides per client type, as `client:access:refresh` with either lifetime left empty to keep the default
+    #[serde(default, deserialize_with = "client_lifetimes")]
+    pub client_lifetimes: HashMap<String, ClientLifetimes>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientLifetimes {
+    pub access: Option<i64>,
+    pub refresh: Option<i64>,
+}
+
+/// Lifetimes in seconds of the tokens issued to a client
+#[derive(Debug, Clone, Copy)]
+pub struct Lifetimes {
+    pub access: i64,
+    pub refresh: i64,
+}
+
+fn client_lifetimes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, ClientLifetimes>, D::Error> {
+    let entries = Vec::<String>::deserialize(deserializer)?;
+    let mut lifetimes = HashMap::new();
+
+    for entry in entries {
+        let parts: Vec<&str> = entry.split(':').collect();
+
+        if parts.len() != 3 || parts[0].is_empty() {
+            return Err(D::Error::custom(format!("expected client:access:refresh, got {}", entry)));
+        }
+
+        let seconds = |part: &str| -> Result<Option<i64>, D::Error> {
+            if part.is_empty() {
+                return Ok(None);
+            }
+            part.parse::<i64>().map(Some).map_err(|_| D::Error::custom(format!("invalid lifetime in {}", entry)))
+        };
+
+        lifetimes.insert(parts[0].to_string(), ClientLifetimes {
+            access: seconds(parts[1])?,
+            refresh: seconds(parts[2])?,
+        });
+    }
+    Ok(lifetimes)
+}
+
+fn default_issuer() -> String {
+    "auth-service".to_string()
+}
+
+fn default_leeway() -> u64 {
+    60
+}
+
+fn default_required_claims() -> Vec<String> {
+    vec!["exp".to_string()]
+}
+
+fn default_access_lifetime() -> i64 {
+    5 * 60
+}
+
+fn default_refresh_lifetime() -> i64 {
+    7 * 24 * 60 * 60
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        TokenConfig {
+            issuer: default_issuer(),
+            audience: Vec::new(),
+            leeway: default_leeway(),
+            required_claims: default_required_claims(),
+            access_lifetime: default_access_lifetime(),
+            refresh_lifetime: default_refresh_lifetime(),
+            client_lifetimes: HashMap::new(),
+        }
+    }
+}
+
+impl TokenConfig {
+    /// Lifetimes for the given client type, falling back to the defaults
+    pub fn lifetimes(&self, client: Option<&str>) -> Lifetimes {
+        let overrides = client
+            .and_then(|c| self.client_lifetimes.get(c))
+            .copied()
+            .unwrap_or_default();
+
+        Lifetimes {
+            access: overrides.access.unwrap_or(self.access_lifetime),
+            refresh: overrides.refresh.unwrap_or(self.refresh_lifetime),
+        }
+    }
+
+    /// Validation of access tokens
+    ///
+    /// A token without `aud` is rejected once audiences are configured, not only one with another audience.
+    pub fn validation(&self) -> Validation {
+        let mut validation = self.base_validation();
+
+        if !self.audience.is_empty() {
+            validation.set_audience(&self.audience);
+            validation.required_spec_claims.insert("aud".to_string());
+        }
+        validation
+    }
+
+    /// Validation of refresh tokens, which are only meant for the issuer itself
+    pub fn refresh_validation(&self) -> Validation {
+        let mut validation = self.base_validation();
+
+        validation.set_audience(&[&self.issuer]);
+        validation.required_spec_claims.insert("aud".to_string());
+        validation
+    }
+
+    /// Validation of refresh tokens issued before the registered claims, which only carry `exp` and `id`
+    ///
+    /// Only their signature and expiry are checked, they can't name a session nor a subject.
+    pub fn legacy_refresh_validation(&self) -> Validation {
+        let mut validation = Validation::default();
+
+        validation.leeway = self.leeway;
+        validation.set_required_spec_claims(&["exp"]);
+        validation
+    }
+
+    fn base_validation(&self) -> Validation {
+        let mut validation = Validation::default();
+
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_required_spec_claims(&self.required_claims);
+        // The issuer is always configured, a token without `iss` would skip its check
+        validation.required_spec_claims.insert("iss".to_string());
+        validation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    fn check(validation: &Validation, claims: Value) -> bool {
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        decode::<Value>(&token, &DecodingKey::from_secret(b"secret"), validation).is_ok()
+    }
+
+    fn secret(hash: &str) -> Secret {
+        Secret {
+            refresh: "refresh".to_string(),
+            access: "access".to_string(),
+            salt: "salt".to_string(),
+            admin: None,
+            hash: hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn hashes_are_keyed() {
+        let hasher = TokenHasher::new(&secret("hash"));
+
+        assert_eq!(hasher.hash("token"), hasher.hash("token"));
+        assert_ne!(hasher.hash("token"), hasher.hash("other"));
+        assert_ne!(hasher.hash("token"), TokenHasher::new(&secret("other hash")).hash("token"));
+        assert_ne!(hasher.hash("token"), TokenHasher::legacy_hash("token"));
+        assert!(hasher.candidates("token").contains(&TokenHasher::legacy_hash("token")));
+    }
+
+    #[test]
+    fn requires_configured_audience_and_issuer() {
+        let config = TokenConfig { audience: vec!["lms".to_string()], ..TokenConfig::default() };
+        let validation = config.validation();
+        let exp = chrono::Utc::now().timestamp() + 60;
+
+        assert!(check(&validation, json!({ "iss": "auth-service", "aud": ["lms"], "exp": exp })));
+        assert!(!check(&validation, json!({ "iss": "auth-service", "aud": ["other"], "exp": exp })));
+        assert!(!check(&validation, json!({ "iss": "auth-service", "exp": exp })));
+        assert!(!check(&validation, json!({ "aud": ["lms"], "exp": exp })));
+    }
+
+    #[test]
+    fn refresh_tokens_are_for_the_issuer() {
+        let validation = TokenConfig::default().refresh_validation();
+        let exp = chrono::Utc::now().timestamp() + 60;
+
+        assert!(check(&validation, json!({ "iss": "auth-service", "aud": ["auth-service"], "exp": exp })));
+        assert!(!check(&validation, json!({ "iss": "auth-service", "exp": exp })));
+    }
+
+    #[test]
+    fn legacy_refresh_tokens_only_need_an_expiry() {
+        let config = TokenConfig::default();
+        let legacy = json!({ "exp": chrono::Utc::now().timestamp() + 60, "id": { "username": "user" } });
+
+        assert!(!check(&config.refresh_validation(), legacy.clone()));
+        assert!(check(&config.legacy_refresh_validation(), legacy));
+        assert!(!check(&config.legacy_refresh_validation(), json!({ "exp": chrono::Utc::now().timestamp() - 120, "id": { "username": "user" } })));
+    }
+}
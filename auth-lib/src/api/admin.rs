@@ -0,0 +1,435 @@
+use actix_web::{Scope, web, HttpResponse, HttpRequest};
+use chrono::Utc;
+use jsonwebtoken::jwk::JwkSet;
+use bson::doc;
+use serde::{Deserialize, Serialize};
+
+use uuid::Uuid;
+
+use crate::{access::{exchange::EXCHANGE_GRANT, extractor::admin::Admin, keyring::{KeyRing, KeyState}, roles::{valid_permission, valid_role, RoleConfig}, tokens::{random_token, TokenConfig, TokenHasher}}, db::{mongo::{MongoDB, uuid_bson}, parser::{client::{find_client, find_clients, OAuthClient, GRANT_TYPES}, user::{find_user, DBParser, User}}}};
+use super::{body::{Body, Validate, FieldError}, sessions::{list, revoke, revoke_all}};
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    message: String,
+}
+
+/// Administration endpoints, authenticated with SECRET__ADMIN or the token of a user with the admin role
+pub fn admin() -> Scope {
+    web::scope("/admin")
+        .route("/keys", web::get().to(list_keys))
+        .route("/keys/{kid}/{state}", web::post().to(set_key_state))
+        .route("/users/{user}/sessions", web::get().to(list_user_sessions))
+        .route("/users/{user}/sessions", web::delete().to(revoke_user_sessions))
+        .route("/users/{user}/sessions/{id}", web::delete().to(revoke_user_session))
+        .route("/users/{user}/roles", web::get().to(get_user_roles))
+        .route("/users/{user}/roles", web::put().to(set_user_roles))
+        .route("/users/{user}/permissions", web::put().to(set_user_permissions))
+        .route("/clients", web::get().to(list_oauth_clients))
+        .route("/clients", web::post().to(create_client))
+        .route("/clients/{id}/secret", web::post().to(rotate_client_secret))
+        .route("/clients/{id}/{state}", web::post().to(set_client_state))
+}
+
+/// Client to register, confidential unless `public` is set or keys are given for `private_key_jwt`
+#[derive(Deserialize, Debug)]
+pub struct NewClient {
+    // Generated when missing
+    pub client_id: Option<String>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub public: bool,
+    // JSON Web Key Set of the client, authenticating with `private_key_jwt` instead of a secret
+    pub jwks: Option<serde_json::Value>,
+
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub grant_types: Vec<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    // Audiences of the service tokens of the client_credentials grant
+    #[serde(default)]
+    pub audiences: Vec<String>,
+
+    // Client type of TOKEN__CLIENT_LIFETIMES, for the lifetimes which aren't given
+    pub client_type: Option<String>,
+    pub access_lifetime: Option<i64>,
+    pub refresh_lifetime: Option<i64>,
+}
+
+impl Validate for NewClient {
+    fn validate(&self) -> Result<(), FieldError> {
+        if self.client_id.as_ref().map(|id| id.trim().is_empty() || id.contains(':')).unwrap_or(false) {
+            return Err(FieldError::new("client_id", "Must not be empty or contain ':'!"));
+        }
+        if self.public && self.jwks.is_some() {
+            return Err(FieldError::new("jwks", "Public clients can't have keys!"));
+        }
+        if let Some(jwks) = &self.jwks {
+            if serde_json::from_value::<JwkSet>(jwks.clone()).is_err() {
+                return Err(FieldError::new("jwks", "Must be a JSON Web Key Set!"));
+            }
+        }
+        if let Some(grant) = self.grant_types.iter().find(|g| !GRANT_TYPES.contains(&g.as_str())) {
+            return Err(FieldError::new("grant_types", &format!("Unsupported grant type {}!", grant)));
+        }
+        // Redirect URIs are absolute and without fragment (RFC 6749 section 3.1.2)
+        if self.redirect_uris.iter().any(|uri| !uri.contains("://") || uri.contains('#')) {
+            return Err(FieldError::new("redirect_uris", "Must be absolute URIs without fragment!"));
+        }
+        if self.grant_types.iter().any(|g| g == "client_credentials" || g == EXCHANGE_GRANT) && self.public {
+            return Err(FieldError::new("grant_types", "Public clients can't use the client_credentials or token exchange grants!"));
+        }
+        if self.grant_types.iter().any(|g| g == "authorization_code") && self.redirect_uris.is_empty() {
+            return Err(FieldError::new("redirect_uris", "Required for the authorization_code grant!"));
+        }
+        if self.access_lifetime.unwrap_or(1) <= 0 || self.refresh_lifetime.unwrap_or(1) <= 0 {
+            return Err(FieldError::new("access_lifetime", "Lifetimes must be positive!"));
+        }
+        Ok(())
+    }
+}
+
+/// Registered client as shown to administrators, the secret is only sent when created or rotated
+#[derive(Serialize)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub name: Option<String>,
+    pub public: bool,
+    pub private_key_jwt: bool,
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub scopes: Vec<String>,
+    pub audiences: Vec<String>,
+    pub client_type: Option<String>,
+    pub access_lifetime: Option<i64>,
+    pub refresh_lifetime: Option<i64>,
+    pub disabled: bool,
+    pub created: chrono::DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(client: OAuthClient, client_secret: Option<String>) -> Self {
+        ClientInfo {
+            public: client.is_public(),
+            private_key_jwt: client.jwks.is_some(),
+            client_id: client.client_id,
+            name: client.name,
+            redirect_uris: client.redirect_uris,
+            grant_types: client.grant_types,
+            scopes: client.scopes,
+            audiences: client.audiences,
+            client_type: client.client_type,
+            access_lifetime: client.access_lifetime,
+            refresh_lifetime: client.refresh_lifetime,
+            disabled: client.disabled,
+            created: client.created,
+            client_secret,
+        }
+    }
+}
+
+/// List the keys of the ring with their current state
+async fn list_keys(_admin: Admin, ring: web::Data<KeyRing>) -> HttpResponse {
+    HttpResponse::Ok().json(ring.status())
+}
+
+/// Move a key to pending, active, retired or expired ahead of its schedule
+async fn set_key_state(_admin: Admin, ring: web::Data<KeyRing>, path: web::Path<(String, String)>) -> HttpResponse {
+    let (kid, state) = path.into_inner();
+
+    let state = match state.as_str() {
+        "pending" => KeyState::Pending,
+        "activate" | "active" => KeyState::Active,
+        "retire" | "retired" => KeyState::Retired,
+        "expire" | "expired" => KeyState::Expired,
+        _ => {
+            return HttpResponse::BadRequest().json(Response { message: format!("Unknown key state {}!", state) });
+        }
+    };
+
+    match ring.set_state(&kid, state) {
+        Ok(status) => {
+            log::info!("Key {} moved to {:?} by administrator", kid, state);
+            HttpResponse::Ok().json(status)
+        }
+        Err(err) => {
+            HttpResponse::BadRequest().json(Response { message: err.to_string() })
+        }
+    }
+}
+
+fn invalid_user(user: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(Response { message: format!("Invalid user id {}!", user) })
+}
+
+/// List the sessions of any user
+async fn list_user_sessions(_admin: Admin, mongodb: web::Data<MongoDB>, path: web::Path<String>) -> HttpResponse {
+    let user = path.into_inner();
+
+    match Uuid::parse_str(&user) {
+        Ok(uuid) => HttpResponse::Ok().json(list(&mongodb, &uuid, None)),
+        Err(_) => invalid_user(&user),
+    }
+}
+
+/// Sign a user out of every device
+async fn revoke_user_sessions(req: HttpRequest, _admin: Admin, mongodb: web::Data<MongoDB>, path: web::Path<String>) -> HttpResponse {
+    let user = path.into_inner();
+
+    match Uuid::parse_str(&user) {
+        Ok(uuid) => {
+            log::info!("Sessions of user {} revoked by administrator", uuid);
+            revoke_all(&req, &mongodb, &uuid, None)
+        }
+        Err(_) => invalid_user(&user),
+    }
+}
+
+async fn revoke_user_session(req: HttpRequest, _admin: Admin, mongodb: web::Data<MongoDB>, path: web::Path<(String, String)>) -> HttpResponse {
+    let (user, id) = path.into_inner();
+
+    match Uuid::parse_str(&user) {
+        Ok(uuid) => {
+            log::info!("Session {} of user {} revoked by administrator", id, uuid);
+            revoke(&req, &mongodb, &uuid, &id)
+        }
+        Err(_) => invalid_user(&user),
+    }
+}
+
+/// Roles to assign to a user, replacing the previous ones
+#[derive(Deserialize, Debug)]
+pub struct NewRoles {
+    pub roles: Vec<String>,
+}
+
+impl Validate for NewRoles {
+    fn validate(&self) -> Result<(), FieldError> {
+        if let Some(role) = self.roles.iter().find(|r| !valid_role(r)) {
+            return Err(FieldError::new("roles", &format!("Invalid role {:?}!", role)));
+        }
+        Ok(())
+    }
+}
+
+/// Permissions to grant to a user on top of its roles, replacing the previous ones
+#[derive(Deserialize, Debug)]
+pub struct NewPermissions {
+    pub permissions: Vec<String>,
+}
+
+impl Validate for NewPermissions {
+    fn validate(&self) -> Result<(), FieldError> {
+        if let Some(permission) = self.permissions.iter().find(|p| !valid_permission(p)) {
+            return Err(FieldError::new("permissions", &format!("Invalid permission {:?}, expected resource:action!", permission)));
+        }
+        Ok(())
+    }
+}
+
+/// Roles and permissions of a user as shown to administrators, with the ones implied by the configuration
+#[derive(Serialize)]
+pub struct RoleInfo {
+    pub user: String,
+    pub roles: Vec<String>,
+    pub effective: Vec<String>,
+    pub permissions: Vec<String>,
+    // Permissions carried in the scope of the user's access tokens
+    pub scope: Vec<String>,
+}
+
+impl RoleInfo {
+    pub fn new(user: &User, config: &RoleConfig) -> Self {
+        let mut effective: Vec<String> = config.effective(&user.roles).into_iter().collect();
+        effective.sort();
+
+        RoleInfo {
+            user: user.uuid.to_string(),
+            roles: user.roles.clone(),
+            effective,
+            permissions: user.permissions.clone(),
+            scope: config.permissions(user).into_iter().collect(),
+        }
+    }
+}
+
+/// User named in the path, if the id is valid and known
+fn target_user(mongodb: &MongoDB, user: &str) -> Option<User> {
+    let uuid = Uuid::parse_str(user).ok()?;
+    find_user(mongodb, doc! { "_id": uuid_bson(&uuid) })
+}
+
+fn user_not_found(user: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(Response { message: format!("User {} not found!", user) })
+}
+
+async fn get_user_roles(_admin: Admin, mongodb: web::Data<MongoDB>, config: web::Data<RoleConfig>, path: web::Path<String>) -> HttpResponse {
+    let user = path.into_inner();
+
+    match target_user(&mongodb, &user) {
+        Some(u) => HttpResponse::Ok().json(RoleInfo::new(&u, &config)),
+        None => user_not_found(&user),
+    }
+}
+
+/// Replace the roles of a user, access tokens carry them from the next login or refresh
+async fn set_user_roles(_admin: Admin, mongodb: web::Data<MongoDB>, config: web::Data<RoleConfig>, path: web::Path<String>, form: Body<NewRoles>) -> HttpResponse {
+    let user = path.into_inner();
+
+    let mut _user = match target_user(&mongodb, &user) {
+        Some(u) => u,
+        None => {
+            return user_not_found(&user);
+        }
+    };
+
+    let mut roles = form.into_inner().roles;
+    roles.sort();
+    roles.dedup();
+
+    match _user.update_roles(&mongodb, roles.clone()) {
+        Ok(_) => {
+            log::info!("Roles of user {} set to {:?} by administrator", _user.uuid, roles);
+            _user.roles = roles;
+            HttpResponse::Ok().json(RoleInfo::new(&_user, &config))
+        }
+        Err(_) => HttpResponse::BadRequest().json(Response { message: String::from("Error while updating the roles in Database") }),
+    }
+}
+
+/// Replace the permissions granted to a user alone, access tokens carry them from the next login or refresh
+async fn set_user_permissions(_admin: Admin, mongodb: web::Data<MongoDB>, config: web::Data<RoleConfig>, path: web::Path<String>, form: Body<NewPermissions>) -> HttpResponse {
+    let user = path.into_inner();
+
+    let mut _user = match target_user(&mongodb, &user) {
+        Some(u) => u,
+        None => {
+            return user_not_found(&user);
+        }
+    };
+
+    let mut permissions = form.into_inner().permissions;
+    permissions.sort();
+    permissions.dedup();
+
+    match _user.update_permissions(&mongodb, permissions.clone()) {
+        Ok(_) => {
+            log::info!("Permissions of user {} set to {:?} by administrator", _user.uuid, permissions);
+            _user.permissions = permissions;
+            HttpResponse::Ok().json(RoleInfo::new(&_user, &config))
+        }
+        Err(_) => HttpResponse::BadRequest().json(Response { message: String::from("Error while updating the permissions in Database") }),
+    }
+}
+
+fn client_not_found(id: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(Response { message: format!("Client {} not found!", id) })
+}
+
+async fn list_oauth_clients(_admin: Admin, mongodb: web::Data<MongoDB>) -> HttpResponse {
+    let clients: Vec<ClientInfo> = find_clients(&mongodb).into_iter().map(|c| ClientInfo::new(c, None)).collect();
+
+    HttpResponse::Ok().json(clients)
+}
+
+/// Register a client, the secret of a confidential client is only returned here
+async fn create_client(_admin: Admin, mongodb: web::Data<MongoDB>, hasher: web::Data<TokenHasher>, config: web::Data<TokenConfig>, form: Body<NewClient>) -> HttpResponse {
+    let form = form.into_inner();
+    let client_id = form.client_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    if let Some(client_type) = &form.client_type {
+        if !config.client_lifetimes.contains_key(client_type) {
+            return HttpResponse::BadRequest().json(Response { message: format!("Unknown client type {}!", client_type) });
+        }
+    }
+
+    if find_client(&mongodb, &client_id).is_some() {
+        return HttpResponse::Conflict().json(Response { message: format!("Client {} already exists!", client_id) });
+    }
+
+    // Public clients and those with keys don't get a secret
+    let secret = if form.public || form.jwks.is_some() { None } else { Some(random_token()) };
+
+    let client = OAuthClient {
+        client_id,
+        name: form.name,
+        secret_hash: secret.as_ref().map(|s| hasher.hash(s)),
+        jwks: form.jwks.map(|j| j.to_string()),
+        redirect_uris: form.redirect_uris,
+        grant_types: form.grant_types,
+        scopes: form.scopes,
+        audiences: form.audiences,
+        client_type: form.client_type,
+        access_lifetime: form.access_lifetime,
+        refresh_lifetime: form.refresh_lifetime,
+        disabled: false,
+        created: Utc::now(),
+    };
+
+    match client.insert(&mongodb) {
+        Ok(_) => {
+            log::info!("Client {} registered by administrator", client.client_id);
+            HttpResponse::Created().json(ClientInfo::new(client, secret))
+        }
+        Err(_) => HttpResponse::BadRequest().json(Response { message: String::from("Error while registering the client in Database") }),
+    }
+}
+
+/// Replace the secret of a confidential client, the previous one stops working at once
+async fn rotate_client_secret(_admin: Admin, mongodb: web::Data<MongoDB>, hasher: web::Data<TokenHasher>, path: web::Path<String>) -> HttpResponse {
+    let id = path.into_inner();
+
+    let client = match find_client(&mongodb, &id) {
+        Some(c) => c,
+        None => {
+            return client_not_found(&id);
+        }
+    };
+
+    if client.secret_hash.is_none() {
+        return HttpResponse::BadRequest().json(Response { message: format!("Client {} doesn't authenticate with a secret!", id) });
+    }
+
+    let secret = random_token();
+
+    match client.update_secret(&mongodb, hasher.hash(&secret)) {
+        Ok(_) => {
+            log::info!("Secret of client {} rotated by administrator", id);
+            HttpResponse::Ok().json(ClientInfo::new(client, Some(secret)))
+        }
+        Err(_) => HttpResponse::BadRequest().json(Response { message: String::from("Error while updating the client in Database") }),
+    }
+}
+
+/// Disable or enable a client, disabled clients can't authenticate nor refresh their sessions
+async fn set_client_state(_admin: Admin, mongodb: web::Data<MongoDB>, path: web::Path<(String, String)>) -> HttpResponse {
+    let (id, state) = path.into_inner();
+
+    let disabled = match state.as_str() {
+        "disable" => true,
+        "enable" => false,
+        _ => {
+            return HttpResponse::BadRequest().json(Response { message: format!("Unknown client state {}!", state) });
+        }
+    };
+
+    let mut client = match find_client(&mongodb, &id) {
+        Some(c) => c,
+        None => {
+            return client_not_found(&id);
+        }
+    };
+
+    match client.update_disabled(&mongodb, disabled) {
+        Ok(_) => {
+            log::info!("Client {} {}d by administrator", id, state);
+            client.disabled = disabled;
+            HttpResponse::Ok().json(ClientInfo::new(client, None))
+        }
+        Err(_) => HttpResponse::BadRequest().json(Response { message: String::from("Error while updating the client in Database") }),
+    }
+}
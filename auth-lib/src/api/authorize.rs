@@ -1,8 +1,8 @@
 use actix_web::{Scope, web, HttpResponse, http::StatusCode, HttpRequest};
 use bson::doc;
-use jsonwebtoken::{ DecodingKey, Validation, TokenData, decode };
 use serde::{Deserialize, Serialize};
-use crate::{db::{mongo::{MongoDB, find_one}, parser::user::{User, DBParser}}, access::{tokenize::parser::{encode_refresh_token, encode_access_token}, extractor::extract::RefreshClaims}};
+use crate::{db::{mongo::{MongoDB, find_one, uuid_bson}, parser::{client::find_client, user::{User, DBParser, find_user}, session::{Session, Origin, find_session, delete_sessions}, audit::AuditEvent}}, access::{dpop::{verify_proof, DpopError}, tokenize::parser::{encode_refresh_token, encode_access_token}, extractor::extract::{RefreshClaims, Token}, keyring::{KeyRing, KeyUse}, roles::RoleConfig, tokens::{TokenConfig, TokenHasher}, revocation::Revocations}};
+use super::{body::{Body, Validate, FieldError, required}, sessions::{sessions, not_first_party, origin, session_lifetimes, revoke_tokens, IssueError, Issuer}};
 use uuid::Uuid;
 extern crate argon2;
 
@@ -25,18 +25,23 @@ pub struct RegisterForm {
 pub struct LoginForm {
     pub username: String,
     pub password: String,
+
+    // Client type selecting the token lifetimes, e.g. `mobile` or `lab`
+    pub client: Option<String>,
+    // Label of the signed-in device, the User-Agent is used when missing
+    pub device: Option<String>,
 }
 
 /// Trait for password verification against hash using bcrypt algorithm
 pub trait Authorize {
-    fn verify_pwsh(&self, hash: &String) -> bool;
+    fn verify_pwsh(&self, hash: &str) -> bool;
 }
 
 impl Authorize for LoginForm {
-    fn verify_pwsh(&self, hash: &String) -> bool {
+    fn verify_pwsh(&self, hash: &str) -> bool {
         let _pws = self.password.clone();
 
-        argon2::verify_encoded(&hash, &_pws.as_bytes()).unwrap()
+        argon2::verify_encoded(hash, _pws.as_bytes()).unwrap()
     }
 }
 
@@ -51,7 +56,27 @@ impl Generate for RegisterForm {
 
         let config = argon2::Config::default();
 
-        argon2::hash_encoded(&_pws.as_bytes(), salt, &config).unwrap_or("".to_string())
+        argon2::hash_encoded(_pws.as_bytes(), salt, &config).unwrap_or("".to_string())
+    }
+}
+
+impl Validate for RegisterForm {
+    fn validate(&self) -> Result<(), FieldError> {
+        required("username", &self.username)?;
+        required("email", &self.email)?;
+        required("password", &self.password)?;
+
+        if !self.email.contains('@') {
+            return Err(FieldError::new("email", "Invalid email address!"));
+        }
+        Ok(())
+    }
+}
+
+impl Validate for LoginForm {
+    fn validate(&self) -> Result<(), FieldError> {
+        required("username", &self.username)?;
+        required("password", &self.password)
     }
 }
 
@@ -59,6 +84,11 @@ impl Generate for RegisterForm {
 struct LoginResponse {
     access_token: String,
     refresh_token: String,
+    // `DPoP` when the tokens are bound to the key of a DPoP proof, `Bearer` otherwise
+    token_type: String,
+    // Seconds until each token expires
+    expires_in: i64,
+    refresh_expires_in: i64,
 }
 
 #[derive(Serialize,Deserialize)]
@@ -71,9 +101,30 @@ struct RefreshForm {
     token: String,
 }
 
+impl Validate for RefreshForm {
+    fn validate(&self) -> Result<(), FieldError> {
+        required("token", &self.token)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogoutForm {
+    token: String,
+}
+
+impl Validate for LogoutForm {
+    fn validate(&self) -> Result<(), FieldError> {
+        required("token", &self.token)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct RefreshResponse {
     access_token: String,
+    refresh_token: String,
+    token_type: String,
+    expires_in: i64,
+    refresh_expires_in: i64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -92,10 +143,13 @@ pub fn authorize() -> Scope {
         .route("/register", web::post().to(register))
         .route("/validate", web::get().to(validate))
         .route("/refresh", web::post().to(refresh))
+        .route("/logout", web::post().to(logout))
+        .route("/logout-all", web::post().to(logout_all))
+        .service(sessions())
 }
 
 /// Register should add user from POST form to database and return 200 OK
-async fn register(req: HttpRequest, form: web::Form<RegisterForm>) -> HttpResponse {
+async fn register(req: HttpRequest, form: Body<RegisterForm>) -> HttpResponse {
     let data = req.app_data::<web::Data<MongoDB>>().unwrap().database.clone();
     let client = req.app_data::<web::Data<MongoDB>>().unwrap().client.clone();
 
@@ -110,7 +164,7 @@ async fn register(req: HttpRequest, form: web::Form<RegisterForm>) -> HttpRespon
     }
 
     let _mongodb = MongoDB {
-        client: client,
+        client,
         database: data,
     };
 
@@ -128,7 +182,7 @@ async fn register(req: HttpRequest, form: web::Form<RegisterForm>) -> HttpRespon
     };
     
     if find_one(&_mongodb, filter_email) || find_one(&_mongodb, filter_username) {
-        return HttpResponse::BadRequest().body(format!("Username or Email already in use!"));
+        return HttpResponse::BadRequest().body("Username or Email already in use!");
     }
 
     // Create a new user
@@ -138,9 +192,11 @@ async fn register(req: HttpRequest, form: web::Form<RegisterForm>) -> HttpRespon
         email: form.email.clone(),
         password_hash: form.generate_pwsh(_secret.unwrap().salt.as_bytes()),
         name: form.name.clone(),
-        refresh_token: encode_refresh_token(form.username.clone(), _secret.unwrap()),
-        //refresh_token: "".to_string(),
+        refresh_token: None,
+        refresh_hash: None,
         photo_url: form.photo_url.clone(),
+        roles: Vec::new(),
+        permissions: Vec::new(),
         refresh_creation: chrono::offset::Utc::now(),
     };
 
@@ -156,31 +212,28 @@ async fn register(req: HttpRequest, form: web::Form<RegisterForm>) -> HttpRespon
             )
         }
         Err(_) => {
-            HttpResponse::BadRequest().body(format!("Could not create user!"))
+            HttpResponse::BadRequest().body("Could not create user!")
         }
     }
 }
 
 /// Login should check user's identity from POST form return access and refresh tokens
-async fn login(req: HttpRequest, form: web::Form<LoginForm>) -> HttpResponse {
+async fn login(req: HttpRequest, form: Body<LoginForm>, ring: web::Data<KeyRing>, config: web::Data<TokenConfig>, hasher: web::Data<TokenHasher>, roles: web::Data<RoleConfig>) -> HttpResponse {
     let data = req.app_data::<web::Data<MongoDB>>().unwrap().database.clone();
     let client = req.app_data::<web::Data<MongoDB>>().unwrap().client.clone();
 
-    // Get secret from local data
-    let _secret = req.app_data::<web::Data<Secret>>();
-
-    match _secret {
-        Some(_) => {}
-        None => {
-            return HttpResponse::new(StatusCode::BAD_REQUEST);
-        }
-    }
-
     let _mongodb = MongoDB {
-        client: client,
+        client,
         database: data,
     };
 
+    // Only the client types of TOKEN__CLIENT_LIFETIMES may be asked for, OAuth clients get their tokens from /oauth/token
+    if let Some(client) = &form.client {
+        if !config.client_lifetimes.contains_key(client) {
+            return HttpResponse::BadRequest().json(Response { message: format!("Unknown client type {}!", client) });
+        }
+    }
+
     // Check if username exists in DB
     let _username = form.username.clone();
 
@@ -189,7 +242,7 @@ async fn login(req: HttpRequest, form: web::Form<LoginForm>) -> HttpResponse {
     };
 
     if !find_one(&_mongodb, filter_username.clone()) {
-        return HttpResponse::BadRequest().body(format!("Username not found!"));
+        return HttpResponse::BadRequest().body("Username not found!");
     }
 
     // Verify password with hash
@@ -200,92 +253,310 @@ async fn login(req: HttpRequest, form: web::Form<LoginForm>) -> HttpResponse {
     let _password_hash = _user.password_hash.clone();
     
     if !form.verify_pwsh(&_password_hash) {
-        return HttpResponse::BadRequest().body(format!("Password doesn't match!"));   
+        return HttpResponse::BadRequest().body("Password doesn't match!");   
     }
 
-    // Return access and refresh JWT
-    let _refresh_jwt = _user.refresh_token.clone();
-
-    // Decode Token to verify if it expired
-    let decoded: Result<TokenData<RefreshClaims>, jsonwebtoken::errors::Error> = decode::<RefreshClaims>(
-        &_refresh_jwt,
-        &DecodingKey::from_secret(_secret.unwrap().refresh.as_str().as_ref()),
-        &Validation::new(jsonwebtoken::Algorithm::HS256)
-    );
-
-    match decoded {
-        Ok(_) => {},
+    // Tokens are bound to the key of the DPoP proof, when one is sent
+    let _proof = match verify_proof(&req, None) {
+        Ok(p) => p,
         Err(err) => {
-            // Refresh JWT has expired, generate a new one
-            if let jsonwebtoken::errors::ErrorKind::ExpiredSignature = err.clone().into_kind() {
-                let _new_token = encode_refresh_token(form.username.clone(), &_secret.unwrap());
-
-                match _user.update_token(&_mongodb, _new_token) {
-                    Ok(_) => {},
-                    Err(_) => {
-                        return HttpResponse::BadRequest().json(Response {message: String::from("Error while updating the refresh token in Database")});
-                    }
-                }
-            }
-            return HttpResponse::BadRequest().json(Response {message: format!("Could not parse Refresh Token because of {:?}", err.clone().into_kind())});   
+            return dpop_error(err);
         }
-    }
+    };
+
+    // Every login opens a new session with its own refresh token
+    let issuer = Issuer {
+        mongodb: &_mongodb,
+        ring: &ring,
+        config: &config,
+        hasher: &hasher,
+        roles: &roles,
+        jkt: _proof.map(|p| p.jkt),
+    };
 
-    let access_token = encode_access_token(_user.username.clone(), _user.name.unwrap_or("".to_string()).clone(), &_secret.unwrap());
-    let refresh_token: String = _user.refresh_token.clone();
+    let _issued = match issuer.open_session(&_user, origin(&req, form.device.clone()), form.client.clone(), None, config.lifetimes(form.client.as_deref()), true) {
+        Ok(i) => i,
+        Err(IssueError::Key(err)) => {
+            log::error!("Could not sign the tokens of a login: {}", err);
+            return HttpResponse::InternalServerError().json(Response {message: String::from("Could not sign the tokens")});
+        }
+        Err(IssueError::Database(_)) => {
+            return HttpResponse::BadRequest().json(Response {message: String::from("Error while creating the session in Database")});
+        }
+    };
 
     HttpResponse::Ok().json(
         LoginResponse {
-            access_token,
-            refresh_token
+            access_token: _issued.access_token,
+            refresh_token: _issued.refresh_token,
+            token_type: issuer.token_type().to_string(),
+            expires_in: _issued.lifetimes.access,
+            refresh_expires_in: _issued.lifetimes.refresh,
         }
     )
 }
 
-/// Silent refresh should find token's user in database and respond with a new access token
-async fn refresh(req: HttpRequest, form: web::Form<RefreshForm>) -> HttpResponse {
+/// Silent refresh should verify the token, rotate it in its session and respond with new access and refresh tokens
+///
+/// The token's signature, expiry, session and subject are all checked before anything is issued.
+/// Presenting a refresh token which was already rotated means it was stolen, the whole session is revoked.
+async fn refresh(req: HttpRequest, form: Body<RefreshForm>, ring: web::Data<KeyRing>, config: web::Data<TokenConfig>, hasher: web::Data<TokenHasher>, roles: web::Data<RoleConfig>) -> HttpResponse {
     let data = req.app_data::<web::Data<MongoDB>>().unwrap().database.clone();
     let client = req.app_data::<web::Data<MongoDB>>().unwrap().client.clone();
 
-    // Get secret from local data
-    let _secret = req.app_data::<web::Data<Secret>>();
+    let _mongodb = MongoDB {
+        client,
+        database: data,
+    };
 
-    match _secret {
-        Some(_) => {}
-        None => {
-            return HttpResponse::new(StatusCode::BAD_REQUEST);
+    let _claims = match decode_refresh(&ring, &config, &form.token) {
+        Ok(claims) => claims,
+        Err(err) => {
+            if let jsonwebtoken::errors::ErrorKind::ExpiredSignature = err.into_kind() {
+                return session_expired();
+            }
+            return HttpResponse::BadRequest().json(Response { message: String::from("Refresh Token doesn't exist. Please redirect user to login.")})
+        }
+    };
+
+    let _session = match Uuid::parse_str(&_claims.sid).ok().and_then(|sid| find_session(&_mongodb, &sid)) {
+        Some(s) => s,
+        // Tokens issued before sessions were introduced are stored on the user, move them to a session
+        None => match migrate_session(&_mongodb, &hasher, &form.token, origin(&req, None), _claims.exp as i64) {
+            Some(s) => s,
+            None => {
+                return HttpResponse::BadRequest().json(Response { message: String::from("Refresh Token doesn't exist. Please redirect user to login.")})
+            }
         }
+    };
+
+    if _session.expires < chrono::offset::Utc::now() {
+        let _ = _session.delete(&_mongodb);
+        revoke_tokens(&req, &_mongodb, &[_session]);
+        return session_expired();
     }
 
-    let _mongodb = MongoDB {
-        client: client,
-        database: data,
+    // Sessions of a disabled client end with it
+    if !_session.first_party && _session.client.as_deref().and_then(|id| find_client(&_mongodb, id)).map(|c| c.disabled).unwrap_or(false) {
+        let _ = _session.delete(&_mongodb);
+        revoke_tokens(&req, &_mongodb, &[_session]);
+        return HttpResponse::Unauthorized().json(Response { message: String::from("Client is disabled. Please redirect user to login.")})
+    }
+
+    // Sessions bound to a DPoP key are only refreshed with a proof of that key
+    if let Some(jkt) = &_session.jkt {
+        match verify_proof(&req, None) {
+            Ok(Some(proof)) if proof.jkt == *jkt => {}
+            Ok(_) => {
+                return HttpResponse::Unauthorized().json(Response { message: String::from("Refresh Token is bound to a key, a DPoP proof of that key is required.")})
+            }
+            Err(err) => {
+                return dpop_error(err);
+            }
+        }
+    }
+
+    let _user = match find_user(&_mongodb, doc! { "_id": uuid_bson(&_session.user) }) {
+        Some(u) => u,
+        None => {
+            return HttpResponse::BadRequest().json(Response { message: String::from("Refresh Token doesn't exist. Please redirect user to login.")})
+        }
     };
 
-    let filter_token = doc! {
-        "refresh_token": form.token.clone()
+    // The token must name the user of its session, tokens issued before the `sub` claim only carry the username
+    let bound = if _claims.sub.is_empty() {
+        _claims.id.username == _user.username
+    } else {
+        _claims.sub == _user.uuid.to_string()
     };
 
-    if !find_one(&_mongodb, filter_token.clone()) {
-        return HttpResponse::BadRequest().json(Response { message: String::from("Refresh Token doesn't exist. Please redirect user to login.")})
+    if !bound {
+        AuditEvent::new(
+            "refresh_token_subject_mismatch",
+            Some(_user.uuid),
+            req.connection_info().realip_remote_addr().map(|ip| ip.to_string()),
+            doc! { "session": _session.uuid.to_string(), "sub": _claims.sub.clone(), "jti": _claims.jti.clone() },
+        ).record(&_mongodb);
+
+        return HttpResponse::Unauthorized().json(Response { message: String::from("Refresh Token doesn't belong to this session. Please redirect user to login.")})
     }
 
-    let _coll = _mongodb.client.database(&_mongodb.database.name).collection::<User>(&_mongodb.database.collection);
-    let _cursor_user = _coll.find(filter_token.clone(), None).unwrap();
+    // The client of the session was checked when it opened, the request can't pick other lifetimes
+    let lifetimes = session_lifetimes(&_mongodb, &config, &_session);
+    let expires = chrono::offset::Utc::now() + chrono::Duration::seconds(lifetimes.refresh);
+    let refresh_token = match encode_refresh_token(&_user.uuid, _user.username.clone(), &_session.uuid.to_string(), lifetimes.refresh, &ring, &config) {
+        Ok(t) => t,
+        Err(err) => {
+            log::error!("Could not sign a refresh token: {}", err);
+            return HttpResponse::InternalServerError().json(Response {message: String::from("Could not sign the tokens")});
+        }
+    };
 
-    let _user = _cursor_user.deserialize_current().unwrap();
+    let rotated = match _session.rotate(&_mongodb, &hasher.candidates(&form.token), hasher.hash(&refresh_token), expires, &origin(&req, None)) {
+        Ok(res) => res.modified_count == 1,
+        Err(_) => {
+            return HttpResponse::BadRequest().json(Response {message: String::from("Error while updating the refresh token in Database")});
+        }
+    };
+
+    if !rotated {
+        // The token is genuine but no longer current in its session: it was used before
+        let _ = _session.delete(&_mongodb);
+        revoke_tokens(&req, &_mongodb, std::slice::from_ref(&_session));
 
-    let access_token = encode_access_token(_user.username.clone(), _user.name.unwrap_or("".to_string()).clone(), _secret.unwrap());
+        AuditEvent::new(
+            "refresh_token_reuse",
+            Some(_user.uuid),
+            req.connection_info().realip_remote_addr().map(|ip| ip.to_string()),
+            doc! { "session": _session.uuid.to_string(), "jti": _claims.jti.clone() },
+        ).record(&_mongodb);
+
+        return HttpResponse::Unauthorized().json(Response { message: String::from("Refresh Token was already used. The session was revoked, please redirect user to login.")})
+    }
+
+    let access_token = match encode_access_token(&_user, &_session, lifetimes.access, &roles, &ring, &config) {
+        Ok(t) => t,
+        Err(err) => {
+            log::error!("Could not sign an access token: {}", err);
+            return HttpResponse::InternalServerError().json(Response {message: String::from("Could not sign the tokens")});
+        }
+    };
 
     HttpResponse::Ok().json(
         RefreshResponse {
-            access_token
+            token_type: String::from(if _session.jkt.is_some() { "DPoP" } else { "Bearer" }),
+            access_token,
+            refresh_token,
+            expires_in: lifetimes.access,
+            refresh_expires_in: lifetimes.refresh,
         }
     )
 }
 
+/// Add the access token to the revocation list, when one is registered
+fn revoke_access(req: &HttpRequest, token: Option<&Token>) {
+    if let (Some(revocations), Some(token)) = (req.app_data::<web::Data<Revocations>>(), token) {
+        revocations.revoke(&token.jti, token.exp);
+    }
+}
+
+/// Invalid DPoP proof, with the server nonce to retry with when one is required
+fn dpop_error(err: DpopError) -> HttpResponse {
+    let mut res = HttpResponse::BadRequest();
+
+    if let Some(nonce) = err.nonce {
+        res.insert_header(("DPoP-Nonce", nonce));
+    }
+    res.json(Response { message: String::from(err.description) })
+}
+
+/// Verify a refresh token presented to the first-party endpoints
+///
+/// Tokens issued before the registered claims only carry `exp` and `id`. They are checked with the legacy
+/// validation, and without a session nor a subject they can only be moved from their user to a session.
+fn decode_refresh(ring: &KeyRing, config: &TokenConfig, token: &str) -> jsonwebtoken::errors::Result<RefreshClaims> {
+    match ring.decode::<RefreshClaims>(token, KeyUse::Refresh, &config.refresh_validation()) {
+        Err(err) if matches!(err.kind(), jsonwebtoken::errors::ErrorKind::MissingRequiredClaim(_)) => {
+            let claims = ring.decode::<RefreshClaims>(token, KeyUse::Refresh, &config.legacy_refresh_validation())?.claims;
+
+            // Current tokens always carry their registered claims, only legacy ones may go without
+            if !claims.sid.is_empty() || !claims.sub.is_empty() {
+                return Err(err);
+            }
+            Ok(claims)
+        }
+        res => res.map(|data| data.claims),
+    }
+}
+
+fn session_expired() -> HttpResponse {
+    HttpResponse::Unauthorized().json(Response { message: String::from("Session expired. Please redirect user to login.")})
+}
+
+/// Logout should end the session of the presented refresh token
+///
+/// The access token sent in the `Authorization` header, if any, is revoked as well.
+async fn logout(req: HttpRequest, form: Body<LogoutForm>, token: Option<Token>, mongodb: web::Data<MongoDB>, ring: web::Data<KeyRing>, config: web::Data<TokenConfig>, hasher: web::Data<TokenHasher>) -> HttpResponse {
+    let _claims = match decode_refresh(&ring, &config, &form.token) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return HttpResponse::BadRequest().json(Response { message: String::from("Refresh Token doesn't exist.")})
+        }
+    };
+
+    let revoked = match Uuid::parse_str(&_claims.sid).ok().and_then(|sid| find_session(&mongodb, &sid)) {
+        Some(_session) => {
+            let deleted = _session.delete(&mongodb).is_ok();
+            revoke_tokens(&req, &mongodb, &[_session]);
+            deleted
+        }
+        // Tokens issued before sessions were introduced are stored on the user
+        None => match find_user(&mongodb, doc! { "refresh_hash": { "$in": hasher.candidates(&form.token) } }) {
+            Some(_user) => _user.revoke_token(&mongodb).is_ok(),
+            None => {
+                return HttpResponse::BadRequest().json(Response { message: String::from("Refresh Token doesn't exist.")})
+            }
+        }
+    };
+
+    if !revoked {
+        return HttpResponse::BadRequest().json(Response {message: String::from("Error while revoking the session in Database")});
+    }
+
+    revoke_access(&req, token.as_ref());
+
+    HttpResponse::Ok().json(Response { message: String::from("Logged out.") })
+}
+
+/// Logout everywhere should end every session of the access token's user and revoke the access token
+async fn logout_all(req: HttpRequest, token: Token, mongodb: web::Data<MongoDB>) -> HttpResponse {
+    if !token.is_first_party() {
+        return not_first_party();
+    }
+
+    let _user = match Uuid::parse_str(&token.sub).ok().and_then(|uuid| find_user(&mongodb, doc! { "_id": uuid_bson(&uuid) })) {
+        Some(u) => u,
+        None => {
+            return HttpResponse::BadRequest().json(Response { message: String::from("User not found!")})
+        }
+    };
+
+    let revoked = match delete_sessions(&mongodb, &_user.uuid, None) {
+        Ok(sessions) => {
+            revoke_tokens(&req, &mongodb, &sessions);
+            sessions.len()
+        }
+        Err(_) => {
+            return HttpResponse::BadRequest().json(Response {message: String::from("Error while revoking the sessions in Database")});
+        }
+    };
+
+    if _user.refresh_hash.is_some() {
+        let _ = _user.revoke_token(&mongodb);
+    }
+
+    revoke_access(&req, Some(&token));
+
+    HttpResponse::Ok().json(Response { message: format!("Logged out of {} sessions.", revoked) })
+}
+
+/// Move the refresh token hash stored on a user document to a new session
+fn migrate_session(mongodb: &MongoDB, hasher: &TokenHasher, token: &str, origin: Origin, exp: i64) -> Option<Session> {
+    let refresh_hash = hasher.hash(token);
+    let _user = find_user(mongodb, doc! { "refresh_hash": { "$in": hasher.candidates(token) } })?;
+
+    let expires = chrono::DateTime::<chrono::Utc>::from_timestamp(exp, 0)?;
+    let mut _session = Session::new(Uuid::new_v4(), _user.uuid, origin, None, None, refresh_hash, expires);
+    _session.first_party = true;
+
+    _session.insert(mongodb).ok()?;
+    let _ = _user.revoke_token(mongodb);
+
+    Some(_session)
+}
+
 // Validation for Access Token
-async fn validate(token: crate::access::extractor::extract::Token) -> HttpResponse {
+async fn validate(token: Token) -> HttpResponse {
     HttpResponse::Ok().json(
         ValidateResponse {
             username: token.username,
@@ -293,3 +564,144 @@ async fn validate(token: crate::access::extractor::extract::Token) -> HttpRespon
         }
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test::{call_service, init_service, read_body, TestRequest}, App};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use crate::{api::testing, db::parser::{session::find_sessions, user::migrate_tokens}};
+
+    /// Refresh token as issued before the registered claims, signed with the refresh secret and without a kid
+    fn legacy_token(claims: Value) -> String {
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(testing::secret().refresh.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn decodes_legacy_refresh_tokens() {
+        let exp = chrono::Utc::now().timestamp() + 60;
+        let claims = decode_refresh(&testing::ring(), &TokenConfig::default(), &legacy_token(json!({ "exp": exp, "id": { "username": "user" } }))).unwrap();
+
+        assert_eq!(claims.id.username, "user");
+        assert!(claims.sid.is_empty() && claims.sub.is_empty());
+    }
+
+    #[test]
+    fn refuses_current_tokens_without_their_claims() {
+        let exp = chrono::Utc::now().timestamp() + 60;
+        let token = legacy_token(json!({ "exp": exp, "sid": "session", "id": { "username": "user" } }));
+
+        assert!(decode_refresh(&testing::ring(), &TokenConfig::default(), &token).is_err());
+    }
+
+    #[test]
+    fn reports_expired_legacy_tokens() {
+        let exp = chrono::Utc::now().timestamp() - 120;
+        let err = decode_refresh(&testing::ring(), &TokenConfig::default(), &legacy_token(json!({ "exp": exp, "id": { "username": "user" } }))).unwrap_err();
+
+        assert!(matches!(err.kind(), jsonwebtoken::errors::ErrorKind::ExpiredSignature));
+    }
+
+    #[actix_web::test]
+    async fn only_first_party_tokens_log_out_everywhere() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(testing::mongodb()))
+                .app_data(web::Data::new(testing::ring()))
+                .app_data(web::Data::new(TokenConfig::default()))
+                .route("/user/logout-all", web::post().to(logout_all))
+        ).await;
+
+        let user = testing::user(&[]);
+        let tokens = [
+            testing::access_token(&user, &testing::session(&user, Some("lms"), Some("openid"))),
+            testing::exchanged_token(&user, &testing::session(&user, None, None)),
+        ];
+
+        for token in tokens {
+            let req = TestRequest::post().uri("/user/logout-all")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_request();
+
+            assert_eq!(call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    async fn post(mongodb: &MongoDB, uri: &str, token: &str) -> (StatusCode, Value) {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(mongodb.clone()))
+                .app_data(web::Data::new(testing::ring()))
+                .app_data(web::Data::new(testing::hasher()))
+                .app_data(web::Data::new(TokenConfig::default()))
+                .app_data(web::Data::new(RoleConfig::default()))
+                .route("/user/refresh", web::post().to(refresh))
+                .route("/user/logout", web::post().to(logout))
+        ).await;
+
+        let req = TestRequest::post().uri(uri).set_json(json!({ "token": token })).to_request();
+        let res = call_service(&app, req).await;
+        let status = res.status();
+
+        (status, serde_json::from_slice(&read_body(res).await).unwrap_or(Value::Null))
+    }
+
+    /// User signed in before sessions, with the plaintext refresh token hashed at startup
+    fn legacy_user(mongodb: &MongoDB) -> (User, String) {
+        let mut user = testing::user(&[]);
+        let token = legacy_token(json!({ "exp": chrono::Utc::now().timestamp() + 600, "id": { "username": user.username.clone() } }));
+
+        user.refresh_token = Some(token.clone());
+        user.insert(mongodb).unwrap();
+        migrate_tokens(mongodb, &testing::hasher()).unwrap();
+
+        (user, token)
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at TEST__MONGO_URL"]
+    async fn moves_legacy_tokens_to_a_session_on_refresh() {
+        let mongodb = testing::mongodb();
+        let (user, token) = legacy_user(&mongodb);
+
+        let (refreshed, body) = post(&mongodb, "/user/refresh", &token).await;
+        let sessions = find_sessions(&mongodb, &user.uuid);
+        let stored = find_user(&mongodb, doc! { "_id": uuid_bson(&user.uuid) }).unwrap();
+        let (logged_out, _) = post(&mongodb, "/user/logout", body["refresh_token"].as_str().unwrap_or_default()).await;
+        let remaining = find_sessions(&mongodb, &user.uuid);
+        testing::drop(&mongodb);
+
+        assert_eq!(refreshed, StatusCode::OK);
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].first_party);
+        assert_eq!(stored.refresh_hash, None);
+        assert_eq!(logged_out, StatusCode::OK);
+        assert!(remaining.is_empty());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at TEST__MONGO_URL"]
+    async fn logs_out_legacy_tokens() {
+        let mongodb = testing::mongodb();
+        let (user, token) = legacy_user(&mongodb);
+
+        let (logged_out, _) = post(&mongodb, "/user/logout", &token).await;
+        let stored = find_user(&mongodb, doc! { "_id": uuid_bson(&user.uuid) }).unwrap();
+        let (refreshed, _) = post(&mongodb, "/user/refresh", &token).await;
+        testing::drop(&mongodb);
+
+        assert_eq!(logged_out, StatusCode::OK);
+        assert_eq!(stored.refresh_hash, None);
+        assert_eq!(refreshed, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn decodes_issued_refresh_tokens() {
+        let user = testing::user(&[]);
+        let token = encode_refresh_token(&user.uuid, user.username.clone(), "session", 60, &testing::ring(), &TokenConfig::default()).unwrap();
+
+        assert_eq!(decode_refresh(&testing::ring(), &TokenConfig::default(), &token).unwrap().sid, "session");
+    }
+}
@@ -0,0 +1,188 @@
+use std::{fmt, future::Future, ops::Deref, pin::Pin};
+
+use actix_web::{error, http::StatusCode, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Request body accepted both as `application/json` and `application/x-www-form-urlencoded`
+pub struct Body<T>(pub T);
+
+impl<T> Body<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Body<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// Trait for checks on a parsed body, shared by every accepted content type
+pub trait Validate {
+    fn validate(&self) -> Result<(), FieldError>;
+}
+
+/// Structured error naming the field of the body which was rejected
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl ResponseError for FieldError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::BadRequest().json(self)
+    }
+}
+
+/// Fail the validation if the given field is empty
+pub fn required(field: &str, value: &str) -> Result<(), FieldError> {
+    if value.trim().is_empty() {
+        return Err(FieldError::new(field, "Field must not be empty!"));
+    }
+    Ok(())
+}
+
+/// Turn a deserialization error into an error naming the offending field
+fn field_error<E: fmt::Display>(err: serde_path_to_error::Error<E>) -> FieldError {
+    let path = err.path().to_string();
+    let message = err.inner().to_string();
+
+    // Missing fields are reported on the parent, the name is only in the message
+    let field = if path == "." || path.is_empty() {
+        message.split('`').nth(1).unwrap_or("body").to_string()
+    } else {
+        path
+    };
+
+    FieldError { field, message }
+}
+
+fn parse<T: DeserializeOwned>(content_type: &str, bytes: &[u8]) -> Result<T, actix_web::Error> {
+    match content_type {
+        "application/json" => {
+            let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+            serde_path_to_error::deserialize(&mut deserializer).map_err(|err| field_error(err).into())
+        }
+        "application/x-www-form-urlencoded" => {
+            let deserializer = serde_urlencoded::Deserializer::new(form_urlencoded::parse(bytes));
+            serde_path_to_error::deserialize(deserializer).map_err(|err| field_error(err).into())
+        }
+        _ => Err(error::ErrorUnsupportedMediaType("Expected application/json or application/x-www-form-urlencoded body!")),
+    }
+}
+
+/// Trait to parse and validate the body based on the request's Content-Type
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for Body<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let content_type = req.content_type().to_string();
+        let bytes = web::Bytes::from_request(req, payload);
+
+        Box::pin(async move {
+            let bytes = bytes.await?;
+            let body: T = parse(&content_type, &bytes)?;
+
+            body.validate()?;
+
+            Ok(Body(body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header::ContentType, test, App};
+    use serde::Deserialize;
+    use serde_json::Value;
+
+    #[derive(Deserialize, Serialize, Debug)]
+    struct Course {
+        name: String,
+        seats: u32,
+    }
+
+    impl Validate for Course {
+        fn validate(&self) -> Result<(), FieldError> {
+            required("name", &self.name)
+        }
+    }
+
+    async fn echo(form: Body<Course>) -> HttpResponse {
+        HttpResponse::Ok().json(form.into_inner())
+    }
+
+    async fn post(content_type: ContentType, body: &'static str) -> (StatusCode, Value) {
+        let app = test::init_service(App::new().route("/", web::post().to(echo))).await;
+        let req = test::TestRequest::post().uri("/").insert_header(content_type).set_payload(body).to_request();
+
+        let res = test::call_service(&app, req).await;
+        let status = res.status();
+        let body = test::read_body(res).await;
+
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[actix_web::test]
+    async fn parses_json_and_forms() {
+        let (status, body) = post(ContentType::json(), r#"{"name": "Algebra", "seats": 30}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["seats"], 30);
+
+        let (status, body) = post(ContentType::form_url_encoded(), "name=Algebra&seats=30").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "Algebra");
+    }
+
+    #[actix_web::test]
+    async fn refuses_other_content_types() {
+        let (status, _) = post(ContentType::plaintext(), "name=Algebra&seats=30").await;
+
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[actix_web::test]
+    async fn names_the_rejected_field() {
+        let (status, body) = post(ContentType::json(), r#"{"name": "Algebra", "seats": "many"}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "seats");
+
+        let (status, body) = post(ContentType::form_url_encoded(), "seats=30").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "name");
+    }
+
+    #[actix_web::test]
+    async fn reports_failed_validation() {
+        let (status, body) = post(ContentType::json(), r#"{"name": " ", "seats": 30}"#).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "name");
+        assert_eq!(body["message"], "Field must not be empty!");
+    }
+}
@@ -0,0 +1,87 @@
+use actix_web::{Scope, web, HttpRequest, HttpResponse, http::header::{CacheControl, CacheDirective}};
+use jsonwebtoken::Algorithm;
+use serde::Serialize;
+
+use crate::{access::{dpop::DPOP_ALGORITHMS, jwks::key_set, keyring::KeyRing, keys::KeyConfig, oidc::{id_token_algorithm, OIDC_SCOPES}, tokens::TokenConfig}, db::parser::client::GRANT_TYPES};
+
+/// OpenID Provider metadata (OpenID Connect Discovery section 3)
+#[derive(Serialize, Debug)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub jwks_uri: String,
+    pub introspection_endpoint: String,
+    pub revocation_endpoint: String,
+    pub device_authorization_endpoint: String,
+
+    pub response_types_supported: Vec<&'static str>,
+    pub grant_types_supported: Vec<&'static str>,
+    pub subject_types_supported: Vec<&'static str>,
+    pub scopes_supported: Vec<&'static str>,
+    pub claims_supported: Vec<&'static str>,
+    pub id_token_signing_alg_values_supported: Vec<Algorithm>,
+    pub token_endpoint_auth_methods_supported: Vec<&'static str>,
+    pub token_endpoint_auth_signing_alg_values_supported: Vec<Algorithm>,
+    pub code_challenge_methods_supported: Vec<&'static str>,
+    pub dpop_signing_alg_values_supported: Vec<Algorithm>,
+}
+
+/// Discovery documents for services consuming the issued tokens
+pub fn well_known() -> Scope {
+    web::scope("/.well-known")
+        .route("/jwks.json", web::get().to(jwks))
+        .route("/openid-configuration", web::get().to(openid_configuration))
+}
+
+/// Public URL of the service: the issuer when it is one, otherwise the host of the request
+pub fn base_url(req: &HttpRequest, config: &TokenConfig) -> String {
+    if config.issuer.starts_with("https://") || config.issuer.starts_with("http://") {
+        return config.issuer.trim_end_matches('/').to_string();
+    }
+
+    let info = req.connection_info();
+    format!("{}://{}", info.scheme(), info.host())
+}
+
+/// Endpoints and capabilities of the service as an OpenID Provider
+async fn openid_configuration(req: HttpRequest, ring: web::Data<KeyRing>, config: web::Data<TokenConfig>) -> HttpResponse {
+    let base = base_url(&req, &config);
+    // OpenID Connect is only offered when ID tokens can be signed with an asymmetric key
+    let algorithms: Vec<Algorithm> = id_token_algorithm(&ring).into_iter().collect();
+
+    HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(3600)]))
+        .json(ProviderMetadata {
+            issuer: config.issuer.clone(),
+            authorization_endpoint: format!("{}/oauth/authorize", base),
+            token_endpoint: format!("{}/oauth/token", base),
+            userinfo_endpoint: format!("{}/oauth/userinfo", base),
+            jwks_uri: format!("{}/.well-known/jwks.json", base),
+            introspection_endpoint: format!("{}/oauth/introspect", base),
+            revocation_endpoint: format!("{}/oauth/revoke", base),
+            device_authorization_endpoint: format!("{}/oauth/device_authorization", base),
+
+            response_types_supported: vec!["code"],
+            grant_types_supported: GRANT_TYPES.to_vec(),
+            subject_types_supported: vec!["public"],
+            scopes_supported: if algorithms.is_empty() { Vec::new() } else { OIDC_SCOPES.to_vec() },
+            claims_supported: vec!["sub", "iss", "aud", "exp", "iat", "auth_time", "nonce", "sid", "preferred_username", "name", "picture", "email"],
+            id_token_signing_alg_values_supported: algorithms,
+            token_endpoint_auth_methods_supported: vec!["client_secret_basic", "client_secret_post", "private_key_jwt", "none"],
+            token_endpoint_auth_signing_alg_values_supported: vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512, Algorithm::PS256, Algorithm::ES256, Algorithm::ES384, Algorithm::EdDSA],
+            code_challenge_methods_supported: vec!["S256"],
+            dpop_signing_alg_values_supported: DPOP_ALGORITHMS.to_vec(),
+        })
+}
+
+/// Public keys of the ring verifying access tokens, consumers are expected to cache them
+async fn jwks(ring: web::Data<KeyRing>, config: web::Data<KeyConfig>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(config.jwks_max_age as u32),
+        ]))
+        .json(key_set(&ring))
+}
@@ -1 +1,8 @@
 pub mod authorize;
+pub mod body;
+pub mod discovery;
+pub mod sessions;
+pub mod oauth;
+pub mod admin;
+#[cfg(test)]
+pub(crate) mod testing;
\ No newline at end of file
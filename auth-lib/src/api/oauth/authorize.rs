@@ -0,0 +1,276 @@
+use actix_web::{cookie::{Cookie, SameSite}, http::StatusCode, web, HttpRequest, HttpResponse};
+use bson::doc;
+use chrono::Utc;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::{access::{keyring::KeyRing, oidc::id_token_algorithm, tokens::{random_token, TokenHasher}}, db::{mongo::MongoDB, parser::{client::find_client, code::AuthorizationCode, user::find_user}}};
+use crate::api::{authorize::{Authorize, LoginForm}, body::{Body, Validate, FieldError, required}};
+
+// Authorization codes are exchanged right after the redirect
+const CODE_LIFETIME: i64 = 60;
+
+// Cookie holding the browser's secret the sign-in forms are tied to
+const CSRF_COOKIE: &str = "oauth_csrf";
+
+/// Parameters of the authorization request (RFC 6749 section 4.1.1 and RFC 7636 section 4.3)
+#[derive(Deserialize, Debug)]
+pub struct AuthorizeQuery {
+    pub response_type: Option<String>,
+    pub client_id: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    // OpenID Connect requests, repeated in the ID token
+    pub nonce: Option<String>,
+
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+/// Credentials entered by the user on the sign-in page
+#[derive(Deserialize, Debug)]
+pub struct SignInForm {
+    pub username: String,
+    pub password: String,
+    // Ties the form to the browser and the authorization request it was shown for
+    pub csrf_token: String,
+}
+
+impl Validate for SignInForm {
+    fn validate(&self) -> Result<(), FieldError> {
+        required("username", &self.username)?;
+        required("password", &self.password)?;
+        required("csrf_token", &self.csrf_token)
+    }
+}
+
+/// Authorization request whose client and redirect URI were checked
+struct Request {
+    client_id: String,
+    redirect_uri: String,
+    scope: Option<String>,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: String,
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Error shown to the user, used when the client can't be trusted with a redirect
+pub fn error_page(message: &str) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("text/html; charset=utf-8")
+        .body(format!("<!DOCTYPE html><html><body><h1>Sign-in failed</h1><p>{}</p></body></html>", escape(message)))
+}
+
+/// CSRF token of a sign-in form, derived from the browser's cookie and the checked request
+fn csrf_token(hasher: &TokenHasher, cookie: &str, request: &Request) -> String {
+    hasher.hash(&[
+        cookie,
+        &request.client_id,
+        &request.redirect_uri,
+        &request.code_challenge,
+        request.state.as_deref().unwrap_or_default(),
+        request.scope.as_deref().unwrap_or_default(),
+        request.nonce.as_deref().unwrap_or_default(),
+    ].join("\n"))
+}
+
+/// Sign-in page, posted back to the same URL so the query is kept
+fn sign_in_page(status: StatusCode, client_id: &str, csrf_token: &str, error: Option<&str>) -> HttpResponse {
+    let error = error.map(|e| format!("<p class=\"error\">{}</p>", escape(e))).unwrap_or_default();
+
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .insert_header(("Cache-Control", "no-store"))
+        .insert_header(("X-Frame-Options", "DENY"))
+        .body(format!(
+            "<!DOCTYPE html><html><body><h1>Sign in to {}</h1>{}\
+             <form method=\"post\">\
+             <input name=\"csrf_token\" type=\"hidden\" value=\"{}\">\
+             <input name=\"username\" placeholder=\"Username\" autocomplete=\"username\">\
+             <input name=\"password\" type=\"password\" placeholder=\"Password\" autocomplete=\"current-password\">\
+             <button type=\"submit\">Sign in</button>\
+             </form></body></html>",
+            escape(client_id), error, escape(csrf_token)
+        ))
+}
+
+/// Send the user back to the client with the given parameters
+fn redirect(redirect_uri: &str, params: &[(&str, &str)], state: Option<&str>) -> HttpResponse {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+
+    for (key, value) in params {
+        query.append_pair(key, value);
+    }
+    if let Some(state) = state {
+        query.append_pair("state", state);
+    }
+
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+
+    HttpResponse::Found()
+        .insert_header(("Location", format!("{}{}{}", redirect_uri, separator, query.finish())))
+        .finish()
+}
+
+/// Rejected authorization request
+enum Rejection {
+    // The client or the redirect URI can't be trusted, the error is shown to the user
+    Show(&'static str),
+    // The error is sent back to the client
+    Redirect { redirect_uri: String, error: &'static str, description: Option<&'static str> },
+}
+
+impl Rejection {
+    fn response(self, state: Option<&str>) -> HttpResponse {
+        match self {
+            Rejection::Show(message) => error_page(message),
+            Rejection::Redirect { redirect_uri, error, description } => {
+                let mut params = vec![("error", error)];
+                if let Some(description) = description {
+                    params.push(("error_description", description));
+                }
+                redirect(&redirect_uri, &params, state)
+            }
+        }
+    }
+}
+
+/// Check the request, errors about the client or the redirect URI are shown and the others redirected
+fn check(query: &AuthorizeQuery, mongodb: &MongoDB, ring: &KeyRing) -> Result<Request, Rejection> {
+    let client = match query.client_id.as_deref().and_then(|id| find_client(mongodb, id)) {
+        Some(c) if !c.disabled => c,
+        _ => {
+            return Err(Rejection::Show("Unknown client!"));
+        }
+    };
+
+    let redirect_uri = match &query.redirect_uri {
+        Some(uri) if client.redirect_allowed(uri) => uri.clone(),
+        _ => {
+            return Err(Rejection::Show("The redirect URI isn't registered for this client!"));
+        }
+    };
+
+    if !client.allows_grant("authorization_code") {
+        return Err(Rejection::Redirect { redirect_uri, error: "unauthorized_client", description: None });
+    }
+
+    if query.response_type.as_deref() != Some("code") {
+        return Err(Rejection::Redirect { redirect_uri, error: "unsupported_response_type", description: None });
+    }
+
+    // PKCE is mandatory and only with S256
+    let code_challenge = match (&query.code_challenge, query.code_challenge_method.as_deref()) {
+        (Some(challenge), Some("S256")) if challenge.len() == 43 => challenge.clone(),
+        _ => {
+            return Err(Rejection::Redirect { redirect_uri, error: "invalid_request", description: Some("code_challenge with the S256 method is required") });
+        }
+    };
+
+    if !client.user_scopes_allowed(query.scope.as_deref().unwrap_or_default(), id_token_algorithm(ring).is_some()) {
+        return Err(Rejection::Redirect { redirect_uri, error: "invalid_scope", description: None });
+    }
+
+    Ok(Request {
+        client_id: client.client_id,
+        redirect_uri,
+        scope: query.scope.clone(),
+        state: query.state.clone(),
+        nonce: query.nonce.clone(),
+        code_challenge,
+    })
+}
+
+/// Authorization endpoint: show the sign-in page of a code flow request
+pub async fn authorize(req: HttpRequest, query: web::Query<AuthorizeQuery>, mongodb: web::Data<MongoDB>, ring: web::Data<KeyRing>, hasher: web::Data<TokenHasher>) -> HttpResponse {
+    let request = match check(&query, &mongodb, &ring) {
+        Ok(r) => r,
+        Err(rejection) => {
+            return rejection.response(query.state.as_deref());
+        }
+    };
+
+    // The secret is kept across requests so sign-in pages open in several tabs stay valid
+    let secret = req.cookie(CSRF_COOKIE).map(|c| c.value().to_string()).unwrap_or_else(random_token);
+
+    let cookie = Cookie::build(CSRF_COOKIE, secret.clone())
+        .path("/oauth")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .finish();
+
+    let mut response = sign_in_page(StatusCode::OK, &request.client_id, &csrf_token(&hasher, &secret, &request), None);
+    let _ = response.add_cookie(&cookie);
+    response
+}
+
+/// Verify the user's password and redirect to the client with a single-use authorization code
+pub async fn sign_in(req: HttpRequest, query: web::Query<AuthorizeQuery>, form: Body<SignInForm>, mongodb: web::Data<MongoDB>, ring: web::Data<KeyRing>, hasher: web::Data<TokenHasher>) -> HttpResponse {
+    let request = match check(&query, &mongodb, &ring) {
+        Ok(r) => r,
+        Err(rejection) => {
+            return rejection.response(query.state.as_deref());
+        }
+    };
+
+    // The form must have been shown to this browser for this very request
+    let expected = match req.cookie(CSRF_COOKIE) {
+        Some(c) => csrf_token(&hasher, c.value(), &request),
+        None => {
+            return error_page("The sign-in form expired, please start over!");
+        }
+    };
+
+    if !bool::from(expected.as_bytes().ct_eq(form.csrf_token.as_bytes())) {
+        return error_page("The sign-in form expired, please start over!");
+    }
+
+    let _user = match find_user(&mongodb, doc! { "username": form.username.clone() }) {
+        Some(u) => u,
+        None => {
+            return sign_in_page(StatusCode::UNAUTHORIZED, &request.client_id, &form.csrf_token, Some("Invalid username or password!"));
+        }
+    };
+
+    // Same password check as the login endpoint
+    let login = LoginForm {
+        username: form.username.clone(),
+        password: form.password.clone(),
+        client: None,
+        device: None,
+    };
+
+    if !login.verify_pwsh(&_user.password_hash) {
+        return sign_in_page(StatusCode::UNAUTHORIZED, &request.client_id, &form.csrf_token, Some("Invalid username or password!"));
+    }
+
+    let code = random_token();
+
+    let _code = AuthorizationCode {
+        code_hash: hasher.hash(&code),
+        client_id: request.client_id,
+        redirect_uri: request.redirect_uri.clone(),
+        user: _user.uuid,
+        code_challenge: request.code_challenge,
+        scope: request.scope,
+        nonce: request.nonce,
+        auth_time: Utc::now(),
+        expires: Utc::now() + chrono::Duration::seconds(CODE_LIFETIME),
+    };
+
+    if _code.insert(&mongodb).is_err() {
+        return redirect(&request.redirect_uri, &[("error", "server_error")], request.state.as_deref());
+    }
+
+    redirect(&request.redirect_uri, &[("code", &code)], request.state.as_deref())
+}
@@ -0,0 +1,196 @@
+use actix_web::{http, web, HttpRequest};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::{access::{revocation::Revocations, tokens::{TokenConfig, TokenHasher}}, db::{mongo::MongoDB, parser::client::{find_client, OAuthClient}}};
+use super::OAuthError;
+
+const JWT_BEARER: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Client authentication sent in the body, with `client_secret_post` or `private_key_jwt`
+#[derive(Deserialize, Debug, Default)]
+pub struct ClientCredentials {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+}
+
+/// Claims of a `private_key_jwt` assertion checked here, the others are validated by jsonwebtoken
+#[derive(Deserialize, Debug)]
+struct AssertionClaims {
+    jti: String,
+    exp: usize,
+}
+
+/// Components of the Basic credentials are form-urlencoded (RFC 6749 section 2.3.1)
+fn form_decode(value: &str) -> String {
+    form_urlencoded::parse(format!("v={}", value).as_bytes())
+        .next()
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default()
+}
+
+fn basic(req: &HttpRequest) -> Option<(String, String)> {
+    let header = req.headers().get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = header.strip_prefix("Basic ")?;
+
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+
+    Some((form_decode(id), form_decode(secret)))
+}
+
+/// Issuer of an assertion, read before its signature is checked to find the client's keys
+fn unverified_issuer(assertion: &str) -> Option<String> {
+    let payload = URL_SAFE_NO_PAD.decode(assertion.split('.').nth(1)?).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+
+    claims.get("iss")?.as_str().map(|iss| iss.to_string())
+}
+
+/// Audiences an assertion may be addressed to: the issuer, and the token or called endpoint under it
+///
+/// Only the configured issuer is trusted, the Host header is chosen by the caller.
+fn audiences(req: &HttpRequest) -> Vec<String> {
+    let config = match req.app_data::<web::Data<TokenConfig>>() {
+        Some(c) => c,
+        None => return Vec::new(),
+    };
+
+    let mut audiences = vec![config.issuer.clone()];
+
+    if config.issuer.starts_with("https://") || config.issuer.starts_with("http://") {
+        let base = config.issuer.trim_end_matches('/');
+        audiences.push(format!("{}/oauth/token", base));
+        audiences.push(format!("{}{}", base, req.path()));
+    }
+    audiences
+}
+
+fn registered(req: &HttpRequest, client_id: &str) -> Result<OAuthClient, OAuthError> {
+    let mongodb = req.app_data::<web::Data<MongoDB>>().ok_or(OAuthError::invalid_client("No client is registered!"))?;
+
+    match find_client(mongodb, client_id) {
+        Some(client) if !client.disabled => Ok(client),
+        _ => Err(OAuthError::invalid_client("Unknown or disabled client!")),
+    }
+}
+
+fn verify_secret(req: &HttpRequest, client: &OAuthClient, secret: &str) -> Result<(), OAuthError> {
+    let hasher = req.app_data::<web::Data<TokenHasher>>().ok_or(OAuthError::invalid_client("Invalid client credentials!"))?;
+
+    match &client.secret_hash {
+        Some(hash) if bool::from(hasher.hash(secret).as_bytes().ct_eq(hash.as_bytes())) => Ok(()),
+        _ => Err(OAuthError::invalid_client("Invalid client credentials!")),
+    }
+}
+
+/// Verify a `private_key_jwt` assertion (RFC 7523 section 3) with the client's registered keys
+fn verify_assertion(req: &HttpRequest, client: &OAuthClient, assertion: &str) -> Result<(), OAuthError> {
+    let invalid = || OAuthError::invalid_client("Invalid client assertion!");
+
+    let jwks: JwkSet = client.jwks.as_deref()
+        .and_then(|j| serde_json::from_str(j).ok())
+        .ok_or(OAuthError::invalid_client("Client has no keys for private_key_jwt!"))?;
+
+    let header = decode_header(assertion).map_err(|_| invalid())?;
+
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }.ok_or_else(invalid)?;
+
+    // Shared secrets can't prove the possession of a private key
+    if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        return Err(invalid());
+    }
+    if jwk.common.algorithm.map(|alg| alg != header.alg).unwrap_or(false) {
+        return Err(invalid());
+    }
+
+    let key = DecodingKey::from_jwk(jwk).map_err(|_| invalid())?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&client.client_id]);
+    validation.sub = Some(client.client_id.clone());
+    validation.set_audience(&audiences(req));
+    validation.set_required_spec_claims(&["exp", "iss", "sub", "aud"]);
+
+    if let Some(config) = req.app_data::<web::Data<TokenConfig>>() {
+        validation.leeway = config.leeway;
+    }
+
+    let claims = decode::<AssertionClaims>(assertion, &key, &validation).map_err(|_| invalid())?.claims;
+
+    // An assertion can only be used once
+    if let Some(revocations) = req.app_data::<web::Data<Revocations>>() {
+        if !revocations.use_once(&format!("assertion:{}:{}", client.client_id, claims.jti), claims.exp) {
+            return Err(OAuthError::invalid_client("Client assertion was already used!"));
+        }
+    }
+    Ok(())
+}
+
+/// Authenticate the calling client with `client_secret_basic`, `client_secret_post` or `private_key_jwt`
+pub fn authenticate(req: &HttpRequest, body: &ClientCredentials) -> Result<OAuthClient, OAuthError> {
+    let assertion = match (body.client_assertion_type.as_deref(), &body.client_assertion) {
+        (Some(JWT_BEARER), Some(assertion)) => Some(assertion),
+        (None, None) => None,
+        _ => {
+            return Err(OAuthError::invalid_request("Unsupported client assertion!"));
+        }
+    };
+
+    match (basic(req), &body.client_secret, assertion) {
+        (Some((client_id, secret)), None, None) => {
+            if body.client_id.as_ref().map(|id| *id != client_id).unwrap_or(false) {
+                return Err(OAuthError::invalid_request("client_id doesn't match the Authorization header!"));
+            }
+
+            let client = registered(req, &client_id)?;
+            verify_secret(req, &client, &secret)?;
+            Ok(client)
+        }
+        (None, Some(secret), None) => {
+            let client_id = body.client_id.as_deref().ok_or(OAuthError::invalid_request("client_id is required!"))?;
+
+            let client = registered(req, client_id)?;
+            verify_secret(req, &client, secret)?;
+            Ok(client)
+        }
+        (None, None, Some(assertion)) => {
+            let client_id = unverified_issuer(assertion).ok_or(OAuthError::invalid_client("Invalid client assertion!"))?;
+
+            if body.client_id.as_ref().map(|id| *id != client_id).unwrap_or(false) {
+                return Err(OAuthError::invalid_request("client_id doesn't match the client assertion!"));
+            }
+
+            let client = registered(req, &client_id)?;
+            verify_assertion(req, &client, assertion)?;
+            Ok(client)
+        }
+        (None, None, None) => Err(OAuthError::invalid_client("Client authentication missing!")),
+        _ => Err(OAuthError::invalid_request("Exactly one client authentication method must be used!")),
+    }
+}
+
+/// Identify the calling client: public clients only send their `client_id`, confidential ones must authenticate
+pub fn identify(req: &HttpRequest, body: &ClientCredentials) -> Result<OAuthClient, OAuthError> {
+    let only_id = basic(req).is_none() && body.client_secret.is_none() && body.client_assertion.is_none();
+
+    if let (Some(client_id), true) = (&body.client_id, only_id) {
+        let client = registered(req, client_id)?;
+
+        if !client.is_public() {
+            return Err(OAuthError::invalid_client("Client authentication missing!"));
+        }
+        return Ok(client);
+    }
+
+    authenticate(req, body)
+}
@@ -0,0 +1,307 @@
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use bson::doc;
+use chrono::Utc;
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{access::{extractor::extract::Token, keyring::KeyRing, oidc::id_token_algorithm, tokens::{random_token, TokenConfig, TokenHasher}}, db::{mongo::MongoDB, parser::{client::find_client, device::{find_user_code, DeviceAuthorization, DeviceStatus, DEVICE_GRANT}, user::find_user}}};
+use crate::api::{authorize::{Authorize, LoginForm}, body::{Body, Validate, FieldError, required}, discovery::base_url};
+use super::{authorize::{escape, error_page}, client::{identify, ClientCredentials}, OAuthError};
+
+// Users have this long to enter the code, in seconds
+pub const DEVICE_CODE_LIFETIME: i64 = 10 * 60;
+// Minimum seconds between two polls of the device
+pub const POLL_INTERVAL: i64 = 5;
+
+// Consonants only, so codes can't spell words nor mix up similar characters (RFC 8628 section 6.1)
+const USER_CODE_CHARSET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    message: String,
+}
+
+/// Device authorization request (RFC 8628 section 3.1)
+#[derive(Deserialize, Debug)]
+pub struct DeviceForm {
+    pub scope: Option<String>,
+
+    #[serde(flatten)]
+    pub client: ClientCredentials,
+}
+
+impl Validate for DeviceForm {
+    fn validate(&self) -> Result<(), FieldError> {
+        Ok(())
+    }
+}
+
+/// Device authorization response (RFC 8628 section 3.2)
+#[derive(Serialize, Debug)]
+pub struct DeviceResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_in: i64,
+    pub interval: i64,
+}
+
+/// Code entered on the verification page, with the user's credentials
+#[derive(Deserialize, Debug)]
+pub struct VerifyForm {
+    pub user_code: String,
+    pub username: String,
+    pub password: String,
+    // `approve` or `deny`
+    pub action: String,
+}
+
+impl Validate for VerifyForm {
+    fn validate(&self) -> Result<(), FieldError> {
+        required("user_code", &self.user_code)?;
+        required("username", &self.username)?;
+        required("password", &self.password)
+    }
+}
+
+/// Decision of a signed-in user on a device, sent by a first-party application
+#[derive(Deserialize, Debug)]
+pub struct DeviceDecision {
+    pub user_code: String,
+    pub approve: bool,
+}
+
+impl Validate for DeviceDecision {
+    fn validate(&self) -> Result<(), FieldError> {
+        required("user_code", &self.user_code)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VerifyQuery {
+    pub user_code: Option<String>,
+}
+
+fn user_code() -> String {
+    (0..USER_CODE_LENGTH)
+        .map(|_| USER_CODE_CHARSET[OsRng.gen_range(0..USER_CODE_CHARSET.len())] as char)
+        .collect()
+}
+
+/// Users may type the code in lowercase, with or without the dash
+fn normalize(user_code: &str) -> String {
+    user_code.chars().filter(|c| c.is_ascii_alphabetic()).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+/// Code as shown to the user, e.g. `WDJB-MJHT`
+fn display(user_code: &str) -> String {
+    let (first, second) = user_code.split_at(user_code.len() / 2);
+    format!("{}-{}", first, second)
+}
+
+/// Start the authorization of a device, which then polls the token endpoint while the user approves it elsewhere
+pub async fn device_authorization(req: HttpRequest, form: Body<DeviceForm>, mongodb: web::Data<MongoDB>, ring: web::Data<KeyRing>, hasher: web::Data<TokenHasher>, config: web::Data<TokenConfig>) -> Result<HttpResponse, OAuthError> {
+    let client = identify(&req, &form.client)?;
+
+    if !client.allows_grant(DEVICE_GRANT) {
+        return Err(OAuthError::new(StatusCode::BAD_REQUEST, "unauthorized_client", "The client may not use the device grant!"));
+    }
+    if !client.user_scopes_allowed(form.scope.as_deref().unwrap_or_default(), id_token_algorithm(&ring).is_some()) {
+        return Err(OAuthError::new(StatusCode::BAD_REQUEST, "invalid_scope", "Scope isn't registered for this client!"));
+    }
+
+    let device_code = random_token();
+
+    let mut device = DeviceAuthorization {
+        user_code: user_code(),
+        device_hash: hasher.hash(&device_code),
+        client_id: client.client_id,
+        scope: form.scope.clone(),
+        status: DeviceStatus::Pending,
+        user: None,
+        auth_time: None,
+        interval: POLL_INTERVAL,
+        last_polled: None,
+        expires: Utc::now() + chrono::Duration::seconds(DEVICE_CODE_LIFETIME),
+    };
+
+    // User codes are the key of the requests, a new one is drawn on the rare collision
+    let mut inserted = device.insert(&mongodb);
+    for _ in 0..3 {
+        if inserted.is_ok() {
+            break;
+        }
+        device.user_code = user_code();
+        inserted = device.insert(&mongodb);
+    }
+
+    if inserted.is_err() {
+        return Err(OAuthError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Could not store the device authorization!"));
+    }
+
+    let verification_uri = format!("{}/oauth/device", base_url(&req, &config));
+    let user_code = display(&device.user_code);
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(DeviceResponse {
+            device_code,
+            verification_uri_complete: format!("{}?user_code={}", verification_uri, user_code),
+            verification_uri,
+            user_code,
+            expires_in: DEVICE_CODE_LIFETIME,
+            interval: POLL_INTERVAL,
+        }))
+}
+
+/// Page asking for the code shown on the device, submitted back as the `user_code` query
+fn code_page(status: StatusCode, message: Option<&str>) -> HttpResponse {
+    let message = message.map(|m| format!("<p class=\"message\">{}</p>", escape(m))).unwrap_or_default();
+
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .insert_header(("Cache-Control", "no-store"))
+        .insert_header(("X-Frame-Options", "DENY"))
+        .body(format!(
+            "<!DOCTYPE html><html><body><h1>Connect a device</h1>{}\
+             <form method=\"get\">\
+             <input name=\"user_code\" placeholder=\"Code shown on the device\" autocomplete=\"off\">\
+             <button type=\"submit\">Continue</button>\
+             </form></body></html>",
+            message
+        ))
+}
+
+/// Verification page, where the user sees which client asks for what, signs in and approves or denies the device
+fn verify_page(status: StatusCode, device: &DeviceAuthorization, mongodb: &MongoDB, message: Option<&str>) -> HttpResponse {
+    let message = message.map(|m| format!("<p class=\"message\">{}</p>", escape(m))).unwrap_or_default();
+
+    let name = find_client(mongodb, &device.client_id)
+        .and_then(|c| c.name)
+        .unwrap_or(device.client_id.clone());
+
+    let scopes: String = device.scope.as_deref().unwrap_or_default()
+        .split_whitespace()
+        .map(|s| format!("<li>{}</li>", escape(s)))
+        .collect();
+    let scopes = if scopes.is_empty() { "<p>It asks to sign in to your account.</p>".to_string() } else { format!("<p>It asks for:</p><ul>{}</ul>", scopes) };
+
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .insert_header(("Cache-Control", "no-store"))
+        .insert_header(("X-Frame-Options", "DENY"))
+        .body(format!(
+            "<!DOCTYPE html><html><body><h1>Connect {}</h1>{}\
+             <p>Check that the device shows the code <strong>{}</strong>.</p>{}\
+             <form method=\"post\">\
+             <input name=\"user_code\" type=\"hidden\" value=\"{}\">\
+             <input name=\"username\" placeholder=\"Username\" autocomplete=\"username\">\
+             <input name=\"password\" type=\"password\" placeholder=\"Password\" autocomplete=\"current-password\">\
+             <button type=\"submit\" name=\"action\" value=\"approve\">Approve</button>\
+             <button type=\"submit\" name=\"action\" value=\"deny\">Deny</button>\
+             </form></body></html>",
+            escape(&name), message, escape(&display(&device.user_code)), scopes, escape(&device.user_code)
+        ))
+}
+
+fn done_page(approved: bool) -> HttpResponse {
+    let message = if approved { "The device is signed in, you can return to it." } else { "The device was denied access." };
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header(("Cache-Control", "no-store"))
+        .body(format!("<!DOCTYPE html><html><body><h1>Connect a device</h1><p>{}</p></body></html>", message))
+}
+
+/// Request of the user code which is still pending
+fn pending(mongodb: &MongoDB, user_code: &str) -> Option<DeviceAuthorization> {
+    find_user_code(mongodb, &normalize(user_code))
+        .filter(|d| d.status == DeviceStatus::Pending && d.expires > Utc::now())
+}
+
+/// Ask for the code, then show the client and scopes of its request before the user signs in
+pub async fn verify(query: web::Query<VerifyQuery>, mongodb: web::Data<MongoDB>) -> HttpResponse {
+    let user_code = match query.user_code.as_deref() {
+        Some(c) if !c.trim().is_empty() => c,
+        _ => {
+            return code_page(StatusCode::OK, None);
+        }
+    };
+
+    match pending(&mongodb, user_code) {
+        Some(device) => verify_page(StatusCode::OK, &device, &mongodb, None),
+        None => code_page(StatusCode::BAD_REQUEST, Some("Unknown or expired code!")),
+    }
+}
+
+/// Verify the user's password and record their decision on the device
+pub async fn verify_sign_in(form: Body<VerifyForm>, mongodb: web::Data<MongoDB>) -> HttpResponse {
+    let device = match pending(&mongodb, &form.user_code) {
+        Some(d) => d,
+        None => {
+            return code_page(StatusCode::BAD_REQUEST, Some("Unknown or expired code!"));
+        }
+    };
+
+    let _user = match find_user(&mongodb, doc! { "username": form.username.clone() }) {
+        Some(u) => u,
+        None => {
+            return verify_page(StatusCode::UNAUTHORIZED, &device, &mongodb, Some("Invalid username or password!"));
+        }
+    };
+
+    // Same password check as the login endpoint
+    let login = LoginForm {
+        username: form.username.clone(),
+        password: form.password.clone(),
+        client: None,
+        device: None,
+    };
+
+    if !login.verify_pwsh(&_user.password_hash) {
+        return verify_page(StatusCode::UNAUTHORIZED, &device, &mongodb, Some("Invalid username or password!"));
+    }
+
+    let approved = form.action == "approve";
+
+    match device.decide(&mongodb, &_user.uuid, approved) {
+        Ok(res) if res.modified_count == 1 => done_page(approved),
+        Ok(_) => code_page(StatusCode::BAD_REQUEST, Some("Unknown or expired code!")),
+        Err(_) => error_page("Could not save the decision, please try again."),
+    }
+}
+
+/// Approve or deny a device for the user of the access token
+///
+/// Only tokens of a first-party login may, those granted to OAuth clients can't.
+pub async fn decide(token: Token, form: Body<DeviceDecision>, mongodb: web::Data<MongoDB>) -> HttpResponse {
+    if !token.is_first_party() {
+        return HttpResponse::Forbidden().json(Response { message: String::from("Tokens of OAuth clients can't approve devices!") });
+    }
+
+    let user = match Uuid::parse_str(&token.sub) {
+        Ok(u) => u,
+        Err(_) => {
+            return HttpResponse::BadRequest().json(Response { message: String::from("Token doesn't name a user!") });
+        }
+    };
+
+    let device = match pending(&mongodb, &form.user_code) {
+        Some(d) => d,
+        None => {
+            return HttpResponse::NotFound().json(Response { message: String::from("Unknown or expired code!") });
+        }
+    };
+
+    match device.decide(&mongodb, &user, form.approve) {
+        Ok(res) if res.modified_count == 1 => {
+            let message = if form.approve { "Device approved." } else { "Device denied." };
+            HttpResponse::Ok().json(Response { message: String::from(message) })
+        }
+        Ok(_) => HttpResponse::NotFound().json(Response { message: String::from("Unknown or expired code!") }),
+        Err(_) => HttpResponse::BadRequest().json(Response { message: String::from("Error while saving the decision in Database") }),
+    }
+}
@@ -0,0 +1,200 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use bson::doc;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{access::{dpop::Confirmation, extractor::extract::{AccessClaims, Actor, RefreshClaims, TokenKind}, keyring::{KeyRing, KeyUse}, revocation::Revocations, tokens::{TokenConfig, TokenHasher}}, db::{mongo::{MongoDB, uuid_bson}, parser::{client::find_client, session::find_session, user::{find_user, User}}}};
+use crate::api::body::{Body, Validate, FieldError, required};
+use super::{client::{authenticate, ClientCredentials}, OAuthError};
+
+#[derive(Deserialize, Debug)]
+pub struct IntrospectForm {
+    pub token: String,
+    // `access_token` or `refresh_token`, only decides which type is tried first
+    pub token_type_hint: Option<String>,
+
+    #[serde(flatten)]
+    pub client: ClientCredentials,
+}
+
+impl Validate for IntrospectForm {
+    fn validate(&self) -> Result<(), FieldError> {
+        required("token", &self.token)
+    }
+}
+
+/// Introspection response (RFC 7662 section 2.2), only `active` is sent for inactive tokens
+#[derive(Serialize, Debug, Default)]
+pub struct Introspection {
+    pub active: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<&'static str>,
+    // Whether an access token was issued to a user or a service client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<TokenKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    // DPoP key the token is bound to (RFC 9449 section 6.2)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub aud: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+}
+
+/// Keys, settings and stores needed to check whether a token is still active
+pub struct Inspector<'a> {
+    pub mongodb: &'a MongoDB,
+    pub ring: &'a KeyRing,
+    pub config: &'a TokenConfig,
+    pub hasher: &'a TokenHasher,
+    pub revocations: Option<&'a Revocations>,
+}
+
+fn owner(mongodb: &MongoDB, sub: &str) -> Option<User> {
+    let uuid = Uuid::parse_str(sub).ok()?;
+    find_user(mongodb, doc! { "_id": uuid_bson(&uuid) })
+}
+
+impl Inspector<'_> {
+    /// Claims of an access token which is valid, not revoked and whose user or client still exists
+    pub fn access(&self, token: &str) -> Option<AccessClaims> {
+        // The audience is reported, checking it is up to the resource server
+        let mut validation = self.config.validation();
+        validation.aud = None;
+        validation.required_spec_claims.remove("aud");
+
+        let claims = self.ring.decode::<AccessClaims>(token, KeyUse::Access, &validation).ok()?.claims;
+
+        if self.revocations.map(|r| r.rejects(&claims.jti, &claims.sid)).unwrap_or(false) {
+            return None;
+        }
+
+        match claims.kind {
+            TokenKind::User => {
+                owner(self.mongodb, &claims.sub)?;
+            }
+            TokenKind::Service => {
+                find_client(self.mongodb, &claims.sub).filter(|c| !c.disabled)?;
+            }
+        }
+        Some(claims)
+    }
+
+    /// Claims of a refresh token which is valid and still the current one of its session
+    pub fn refresh(&self, token: &str) -> Option<RefreshClaims> {
+        let claims = self.ring.decode::<RefreshClaims>(token, KeyUse::Refresh, &self.config.refresh_validation()).ok()?.claims;
+
+        let sid = Uuid::parse_str(&claims.sid).ok()?;
+        let session = find_session(self.mongodb, &sid)?;
+
+        if session.expires < Utc::now() || !self.hasher.candidates(token).contains(&session.refresh_hash) {
+            return None;
+        }
+
+        let user = owner(self.mongodb, &claims.sub)?;
+        if user.uuid != session.user {
+            return None;
+        }
+        Some(claims)
+    }
+
+    fn introspect_access(&self, token: &str) -> Option<Introspection> {
+        let claims = self.access(token)?;
+
+        Some(Introspection {
+            active: true,
+            token_type: Some("access_token"),
+            kind: Some(claims.kind),
+            client_id: claims.client_id,
+            scope: claims.scope,
+            act: claims.act,
+            cnf: claims.cnf,
+            roles: claims.roles,
+            username: Some(claims.custom.username).filter(|u| !u.is_empty()),
+            sub: Some(claims.sub),
+            iss: Some(claims.iss),
+            aud: claims.aud,
+            exp: Some(claims.exp),
+            iat: Some(claims.iat),
+            nbf: Some(claims.nbf),
+            jti: Some(claims.jti),
+            sid: Some(claims.sid).filter(|s| !s.is_empty()),
+        })
+    }
+
+    fn introspect_refresh(&self, token: &str) -> Option<Introspection> {
+        let claims = self.refresh(token)?;
+
+        Some(Introspection {
+            active: true,
+            token_type: Some("refresh_token"),
+            kind: Some(TokenKind::User),
+            client_id: None,
+            scope: None,
+            act: None,
+            cnf: None,
+            roles: Vec::new(),
+            username: Some(claims.id.username),
+            sub: Some(claims.sub),
+            iss: Some(claims.iss),
+            aud: claims.aud,
+            exp: Some(claims.exp),
+            iat: Some(claims.iat),
+            nbf: Some(claims.nbf),
+            jti: Some(claims.jti),
+            sid: Some(claims.sid),
+        })
+    }
+
+    /// Inspect the token as the hinted type first, then as the other one
+    pub fn introspect(&self, token: &str, hint: Option<&str>) -> Introspection {
+        let found = if hint == Some("refresh_token") {
+            self.introspect_refresh(token).or_else(|| self.introspect_access(token))
+        } else {
+            self.introspect_access(token).or_else(|| self.introspect_refresh(token))
+        };
+
+        found.unwrap_or_default()
+    }
+}
+
+/// Token introspection (RFC 7662) for services which can't verify tokens themselves
+pub async fn introspect(req: HttpRequest, form: Body<IntrospectForm>, mongodb: web::Data<MongoDB>, ring: web::Data<KeyRing>, config: web::Data<TokenConfig>, hasher: web::Data<TokenHasher>) -> Result<HttpResponse, OAuthError> {
+    authenticate(&req, &form.client)?;
+
+    let inspector = Inspector {
+        mongodb: &mongodb,
+        ring: &ring,
+        config: &config,
+        hasher: &hasher,
+        revocations: req.app_data::<web::Data<Revocations>>().map(|r| r.get_ref()),
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(inspector.introspect(&form.token, form.token_type_hint.as_deref())))
+}
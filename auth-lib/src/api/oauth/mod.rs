@@ -0,0 +1,151 @@
+pub mod client;
+pub mod authorize;
+pub mod token;
+pub mod device;
+pub mod introspect;
+pub mod revoke;
+pub mod userinfo;
+
+use std::fmt;
+
+use actix_web::{Scope, web, http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+
+use crate::access::{dpop::DpopError, keys::KeyError};
+
+/// OAuth 2.0 endpoints for other applications and services
+pub fn oauth() -> Scope {
+    web::scope("/oauth")
+        .route("/authorize", web::get().to(authorize::authorize))
+        .route("/authorize", web::post().to(authorize::sign_in))
+        .route("/token", web::post().to(token::token))
+        .route("/device_authorization", web::post().to(device::device_authorization))
+        .route("/device", web::get().to(device::verify))
+        .route("/device", web::post().to(device::verify_sign_in))
+        .route("/device/decision", web::post().to(device::decide))
+        .route("/introspect", web::post().to(introspect::introspect))
+        .route("/revoke", web::post().to(revoke::revoke))
+        .route("/userinfo", web::get().to(userinfo::userinfo))
+        .route("/userinfo", web::post().to(userinfo::userinfo))
+}
+
+/// Error response of the OAuth endpoints (RFC 6749 section 5.2)
+#[derive(Serialize, Debug)]
+pub struct OAuthError {
+    pub error: &'static str,
+    pub error_description: String,
+
+    #[serde(skip)]
+    status: StatusCode,
+    // Server nonce to retry with, sent in the DPoP-Nonce header
+    #[serde(skip)]
+    nonce: Option<String>,
+}
+
+impl OAuthError {
+    pub fn new(status: StatusCode, error: &'static str, description: &str) -> Self {
+        OAuthError {
+            error,
+            error_description: description.to_string(),
+            status,
+            nonce: None,
+        }
+    }
+
+    pub fn invalid_request(description: &str) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request", description)
+    }
+
+    pub fn invalid_client(description: &str) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "invalid_client", description)
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.error_description)
+    }
+}
+
+impl ResponseError for OAuthError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status);
+
+        if self.status == StatusCode::UNAUTHORIZED {
+            res.insert_header(("WWW-Authenticate", "Basic realm=\"oauth\""));
+        }
+        if let Some(nonce) = &self.nonce {
+            res.insert_header(("DPoP-Nonce", nonce.clone()));
+        }
+        res.json(self)
+    }
+}
+
+/// Tokens which could not be signed, e.g. without an active key
+impl From<KeyError> for OAuthError {
+    fn from(err: KeyError) -> Self {
+        log::error!("Could not sign a token: {}", err);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Could not sign the token!")
+    }
+}
+
+/// Invalid DPoP proofs of token requests (RFC 9449 section 5)
+impl From<DpopError> for OAuthError {
+    fn from(err: DpopError) -> Self {
+        OAuthError {
+            nonce: err.nonce,
+            ..Self::new(StatusCode::BAD_REQUEST, err.error, err.description)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header::ContentType, test, App};
+
+    use crate::{access::{roles::RoleConfig, tokens::TokenConfig}, api::testing};
+
+    async fn post(uri: &str, token: Option<String>, body: &'static str) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(testing::mongodb()))
+                .app_data(web::Data::new(testing::ring()))
+                .app_data(web::Data::new(testing::hasher()))
+                .app_data(web::Data::new(TokenConfig::default()))
+                .app_data(web::Data::new(RoleConfig::default()))
+                .service(oauth())
+        ).await;
+
+        let mut req = test::TestRequest::post().uri(uri).insert_header(ContentType::form_url_encoded()).set_payload(body);
+        if let Some(token) = token {
+            req = req.insert_header(("Authorization", format!("Bearer {}", token)));
+        }
+
+        test::call_service(&app, req.to_request()).await.status()
+    }
+
+    #[actix_web::test]
+    async fn client_endpoints_require_authentication() {
+        assert_eq!(post("/oauth/introspect", None, "token=abc").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(post("/oauth/revoke", None, "token=abc").await, StatusCode::UNAUTHORIZED);
+
+        let exchange = "grant_type=urn:ietf:params:oauth:grant-type:token-exchange&subject_token=abc&subject_token_type=urn:ietf:params:oauth:token-type:access_token&audience=grades";
+        assert_eq!(post("/oauth/token", None, exchange).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn only_first_party_tokens_decide_on_devices() {
+        let user = testing::user(&[]);
+        let delegated = testing::access_token(&user, &testing::session(&user, Some("lms"), Some("openid")));
+        let exchanged = testing::exchanged_token(&user, &testing::session(&user, None, None));
+
+        assert_eq!(post("/oauth/device/decision", None, "user_code=BCDFGHJK&approve=true").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(post("/oauth/device/decision", Some(delegated), "user_code=BCDFGHJK&approve=true").await, StatusCode::FORBIDDEN);
+        assert_eq!(post("/oauth/device/decision", Some(exchanged), "user_code=BCDFGHJK&approve=true").await, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,99 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{access::{extractor::extract::AccessClaims, keyring::KeyRing, revocation::Revocations, tokens::{TokenConfig, TokenHasher}}, db::{mongo::MongoDB, parser::session::find_session}};
+use crate::api::{body::{Body, Validate, FieldError, required}, sessions::revoke_tokens};
+use super::{client::{authenticate, ClientCredentials}, introspect::Inspector, OAuthError};
+
+#[derive(Deserialize, Debug)]
+pub struct RevokeForm {
+    pub token: String,
+    // `access_token` or `refresh_token`, only decides which type is tried first
+    pub token_type_hint: Option<String>,
+
+    #[serde(flatten)]
+    pub client: ClientCredentials,
+}
+
+impl Validate for RevokeForm {
+    fn validate(&self) -> Result<(), FieldError> {
+        required("token", &self.token)
+    }
+}
+
+/// Revoke the session of a refresh token and its access tokens, returns whether the token was one
+///
+/// Tokens of another client are left alone (RFC 7009 section 2.1).
+fn revoke_refresh(req: &HttpRequest, inspector: &Inspector, token: &str, client_id: &str) -> bool {
+    let claims = match inspector.refresh(token) {
+        Some(c) => c,
+        None => return false,
+    };
+
+    if let Some(session) = Uuid::parse_str(&claims.sid).ok().and_then(|sid| find_session(inspector.mongodb, &sid)) {
+        if session.client.as_deref() != Some(client_id) {
+            log::warn!("Client {} tried to revoke a refresh token of another client", client_id);
+            return true;
+        }
+
+        log::info!("Session {} revoked by client {}", session.uuid, client_id);
+
+        let _ = session.delete(inspector.mongodb);
+        revoke_tokens(req, inspector.mongodb, &[session]);
+    }
+    true
+}
+
+/// Whether the access token was issued to the client, directly or for one of its sessions
+fn issued_to(inspector: &Inspector, claims: &AccessClaims, client_id: &str) -> bool {
+    match &claims.client_id {
+        Some(id) => id == client_id,
+        None => Uuid::parse_str(&claims.sid).ok()
+            .and_then(|sid| find_session(inspector.mongodb, &sid))
+            .map(|s| s.client.as_deref() == Some(client_id))
+            .unwrap_or(false),
+    }
+}
+
+/// Add an access token to the revocation list, returns whether the token was one
+///
+/// Tokens of another client are left alone (RFC 7009 section 2.1).
+fn revoke_access(inspector: &Inspector, token: &str, client_id: &str) -> bool {
+    let claims = match inspector.access(token) {
+        Some(c) => c,
+        None => return false,
+    };
+
+    if !issued_to(inspector, &claims, client_id) {
+        log::warn!("Client {} tried to revoke an access token of another client", client_id);
+        return true;
+    }
+
+    if let Some(revocations) = inspector.revocations {
+        revocations.revoke(&claims.jti, claims.exp);
+        log::info!("Access token {} revoked by client {}", claims.jti, client_id);
+    }
+    true
+}
+
+/// Token revocation (RFC 7009), unknown and already invalid tokens are answered with 200 as well
+pub async fn revoke(req: HttpRequest, form: Body<RevokeForm>, mongodb: web::Data<MongoDB>, ring: web::Data<KeyRing>, config: web::Data<TokenConfig>, hasher: web::Data<TokenHasher>) -> Result<HttpResponse, OAuthError> {
+    let client = authenticate(&req, &form.client)?;
+
+    let inspector = Inspector {
+        mongodb: &mongodb,
+        ring: &ring,
+        config: &config,
+        hasher: &hasher,
+        revocations: req.app_data::<web::Data<Revocations>>().map(|r| r.get_ref()),
+    };
+
+    // The hinted type is tried first
+    let _ = match form.token_type_hint.as_deref() {
+        Some("access_token") => revoke_access(&inspector, &form.token, &client.client_id) || revoke_refresh(&req, &inspector, &form.token, &client.client_id),
+        _ => revoke_refresh(&req, &inspector, &form.token, &client.client_id) || revoke_access(&inspector, &form.token, &client.client_id),
+    };
+
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,533 @@
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bson::doc;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use uuid::Uuid;
+
+use crate::{access::{dpop::{verify_proof, Confirmation}, exchange::{ExchangeConfig, ACCESS_TOKEN_TYPE, EXCHANGE_GRANT}, extractor::extract::RefreshClaims, keyring::{KeyRing, KeyUse}, oidc::{id_token_algorithm, IdClaims}, revocation::Revocations, roles::RoleConfig, tokenize::parser::{encode_access_token, encode_exchanged_token, encode_id_token, encode_refresh_token, encode_service_token}, tokens::{TokenConfig, TokenHasher}}, db::{mongo::{MongoDB, uuid_bson}, parser::{audit::AuditEvent, client::OAuthClient, code::consume_code, device::{consume_device, find_device, DeviceStatus, DEVICE_GRANT}, session::find_session, user::{find_user, User}}}};
+use crate::api::{body::{Body, Validate, FieldError, required}, sessions::{origin, revoke_tokens, IssueError, Issuer}};
+use super::{client::{identify, ClientCredentials}, introspect::Inspector, OAuthError};
+
+/// Token request (RFC 6749 section 4.1.3), the fields used depend on the grant type
+#[derive(Deserialize, Debug)]
+pub struct TokenForm {
+    pub grant_type: String,
+
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+
+    pub device_code: Option<String>,
+
+    pub refresh_token: Option<String>,
+
+    // Token exchange, the authenticated client is the actor
+    pub subject_token: Option<String>,
+    pub subject_token_type: Option<String>,
+    pub actor_token: Option<String>,
+    pub requested_token_type: Option<String>,
+
+    // Space separated scopes and audiences of the client_credentials grant
+    pub scope: Option<String>,
+    pub audience: Option<String>,
+
+    #[serde(flatten)]
+    pub client: ClientCredentials,
+}
+
+impl Validate for TokenForm {
+    fn validate(&self) -> Result<(), FieldError> {
+        required("grant_type", &self.grant_type)
+    }
+}
+
+/// Successful token response (RFC 6749 section 5.1)
+#[derive(Serialize, Debug)]
+pub struct TokenResponse {
+    pub access_token: String,
+    // Type of the token issued by a token exchange (RFC 8693 section 2.2.1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issued_token_type: Option<&'static str>,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    // OpenID Connect ID token, when the `openid` scope was granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+impl OAuthError {
+    pub fn invalid_grant(description: &str) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_grant", description)
+    }
+}
+
+/// Check the PKCE verifier against the S256 challenge (RFC 7636 section 4.6)
+fn verify_pkce(verifier: &str, challenge: &str) -> bool {
+    let valid = (43..=128).contains(&verifier.len())
+        && verifier.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b));
+
+    let computed = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+    valid && bool::from(computed.as_bytes().ct_eq(challenge.as_bytes()))
+}
+
+fn token_response(response: TokenResponse) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .insert_header(("Pragma", "no-cache"))
+        .json(response)
+}
+
+/// Token endpoint, dispatching on the grant type
+pub async fn token(req: HttpRequest, form: Body<TokenForm>, mongodb: web::Data<MongoDB>, ring: web::Data<KeyRing>, config: web::Data<TokenConfig>, hasher: web::Data<TokenHasher>, roles: web::Data<RoleConfig>) -> Result<HttpResponse, OAuthError> {
+    let client = identify(&req, &form.client)?;
+
+    // Tokens are bound to the key of the DPoP proof, when one is sent
+    let proof = verify_proof(&req, None)?;
+
+    if !client.allows_grant(&form.grant_type) {
+        return Err(OAuthError::new(StatusCode::BAD_REQUEST, "unauthorized_client", "The client may not use this grant type!"));
+    }
+
+    let issuer = Issuer {
+        mongodb: &mongodb,
+        ring: &ring,
+        config: &config,
+        hasher: &hasher,
+        roles: &roles,
+        jkt: proof.map(|p| p.jkt),
+    };
+
+    match form.grant_type.as_str() {
+        "authorization_code" => authorization_code(&req, &form, &client, &issuer),
+        "refresh_token" => refresh_token(&req, &form, &client, &issuer),
+        "client_credentials" => client_credentials(&form, &client, &issuer),
+        DEVICE_GRANT => device_code(&req, &form, &client, &issuer),
+        EXCHANGE_GRANT => token_exchange(&req, &form, &client, &issuer),
+        _ => Err(OAuthError::new(StatusCode::BAD_REQUEST, "unsupported_grant_type", "Unsupported grant type!")),
+    }
+}
+
+/// Exchange a single-use authorization code for a new session
+fn authorization_code(req: &HttpRequest, form: &TokenForm, client: &OAuthClient, issuer: &Issuer) -> Result<HttpResponse, OAuthError> {
+    let (code, verifier) = match (&form.code, &form.code_verifier) {
+        (Some(code), Some(verifier)) => (code, verifier),
+        _ => {
+            return Err(OAuthError::invalid_request("code and code_verifier are required!"));
+        }
+    };
+
+    // The code is removed before any check, a failed exchange can't be retried
+    let _code = consume_code(issuer.mongodb, &issuer.hasher.hash(code))
+        .ok_or(OAuthError::invalid_grant("Invalid authorization code!"))?;
+
+    if _code.expires < Utc::now() {
+        return Err(OAuthError::invalid_grant("Authorization code expired!"));
+    }
+    if _code.client_id != client.client_id {
+        return Err(OAuthError::invalid_grant("Authorization code was issued to another client!"));
+    }
+    if form.redirect_uri.as_deref() != Some(_code.redirect_uri.as_str()) {
+        return Err(OAuthError::invalid_grant("redirect_uri doesn't match the authorization request!"));
+    }
+    if !verify_pkce(verifier, &_code.code_challenge) {
+        return Err(OAuthError::invalid_grant("Invalid code_verifier!"));
+    }
+
+    let _user = find_user(issuer.mongodb, doc! { "_id": uuid_bson(&_code.user) })
+        .ok_or(OAuthError::invalid_grant("User no longer exists!"))?;
+
+    user_tokens(req, client, issuer, &_user, _code.scope, _code.auth_time, _code.nonce)
+}
+
+/// Open a session of the user for the client, with an ID token when the `openid` scope was granted
+fn user_tokens(req: &HttpRequest, client: &OAuthClient, issuer: &Issuer, user: &User, scope: Option<String>, auth_time: chrono::DateTime<Utc>, nonce: Option<String>) -> Result<HttpResponse, OAuthError> {
+    let openid = scope.as_deref().unwrap_or_default().split_whitespace().any(|s| s == "openid");
+
+    // The access key may have been replaced by a shared secret since the grant
+    if openid && id_token_algorithm(issuer.ring).is_none() {
+        return Err(OAuthError::new(StatusCode::BAD_REQUEST, "invalid_scope", "ID tokens can't be issued, the openid scope isn't supported!"));
+    }
+
+    let issued = issuer.open_session(user, origin(req, Some(client.client_id.clone())), Some(client.client_id.clone()), Some(scope.clone().unwrap_or_default()), client.lifetimes(issuer.config), false)
+        .map_err(|err| match err {
+            IssueError::Key(err) => OAuthError::from(err),
+            IssueError::Database(_) => OAuthError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Could not create the session!"),
+        })?;
+
+    let id_token = openid.then(|| {
+        let now = Utc::now().timestamp() as usize;
+
+        let claims = IdClaims {
+            iss: issuer.config.issuer.clone(),
+            sub: user.uuid.to_string(),
+            aud: client.client_id.clone(),
+            exp: now + issued.lifetimes.access as usize,
+            iat: now,
+            auth_time: auth_time.timestamp() as usize,
+            nonce,
+            at_hash: None,
+            sid: Some(issued.session.to_string()),
+        };
+        encode_id_token(claims, &issued.access_token, issuer.ring)
+    }).transpose()?;
+
+    Ok(token_response(TokenResponse {
+        access_token: issued.access_token,
+        issued_token_type: None,
+        token_type: issuer.token_type(),
+        expires_in: issued.lifetimes.access,
+        // Only clients registered for the refresh_token grant can use one
+        refresh_token: client.allows_grant("refresh_token").then_some(issued.refresh_token),
+        scope,
+        id_token,
+    }))
+}
+
+/// Rotate the refresh token of a session opened for the client (RFC 6749 section 6)
+///
+/// As with the first-party refresh, presenting a token which was already rotated revokes the session.
+fn refresh_token(req: &HttpRequest, form: &TokenForm, client: &OAuthClient, issuer: &Issuer) -> Result<HttpResponse, OAuthError> {
+    let token = form.refresh_token.as_ref().ok_or(OAuthError::invalid_request("refresh_token is required!"))?;
+
+    let claims = issuer.ring.decode::<RefreshClaims>(token, KeyUse::Refresh, &issuer.config.refresh_validation())
+        .map_err(|_| OAuthError::invalid_grant("Invalid refresh token!"))?
+        .claims;
+
+    let session = Uuid::parse_str(&claims.sid).ok().and_then(|sid| find_session(issuer.mongodb, &sid))
+        .ok_or(OAuthError::invalid_grant("Invalid refresh token!"))?;
+
+    if session.first_party || session.client.as_deref() != Some(client.client_id.as_str()) {
+        return Err(OAuthError::invalid_grant("Refresh token was issued to another client!"));
+    }
+
+    if session.expires < Utc::now() {
+        let _ = session.delete(issuer.mongodb);
+        revoke_tokens(req, issuer.mongodb, &[session]);
+        return Err(OAuthError::invalid_grant("Refresh token expired!"));
+    }
+
+    // Sessions bound to a DPoP key are only refreshed with a proof of that key
+    if session.jkt.is_some() && issuer.jkt != session.jkt {
+        return Err(OAuthError::invalid_grant("Refresh token is bound to a key, a DPoP proof of that key is required!"));
+    }
+
+    let _user = find_user(issuer.mongodb, doc! { "_id": uuid_bson(&session.user) })
+        .filter(|u| u.uuid.to_string() == claims.sub)
+        .ok_or(OAuthError::invalid_grant("Invalid refresh token!"))?;
+
+    // A narrower scope may be asked for the access token, the session keeps its grant
+    let mut granted = session.clone();
+
+    if let Some(requested) = form.scope.as_deref() {
+        let scopes: Vec<&str> = session.scope.as_deref().unwrap_or_default().split_whitespace().collect();
+
+        if !requested.split_whitespace().all(|s| scopes.contains(&s)) {
+            return Err(OAuthError::new(StatusCode::BAD_REQUEST, "invalid_scope", "Scope wasn't granted to this session!"));
+        }
+        granted.scope = Some(requested.split_whitespace().collect::<Vec<&str>>().join(" "));
+    }
+
+    let lifetimes = client.lifetimes(issuer.config);
+    let expires = Utc::now() + chrono::Duration::seconds(lifetimes.refresh);
+    let refresh_token = encode_refresh_token(&_user.uuid, _user.username.clone(), &session.uuid.to_string(), lifetimes.refresh, issuer.ring, issuer.config)?;
+
+    let rotated = session.rotate(issuer.mongodb, &issuer.hasher.candidates(token), issuer.hasher.hash(&refresh_token), expires, &origin(req, Some(client.client_id.clone())))
+        .map_err(|_| OAuthError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Could not update the session!"))?;
+
+    if rotated.modified_count != 1 {
+        // The token is genuine but no longer current in its session: it was used before
+        let _ = session.delete(issuer.mongodb);
+        revoke_tokens(req, issuer.mongodb, std::slice::from_ref(&session));
+
+        AuditEvent::new(
+            "refresh_token_reuse",
+            Some(_user.uuid),
+            req.connection_info().realip_remote_addr().map(|ip| ip.to_string()),
+            doc! { "session": session.uuid.to_string(), "jti": claims.jti.clone(), "client": client.client_id.clone() },
+        ).record(issuer.mongodb);
+
+        return Err(OAuthError::invalid_grant("Refresh token was already used, the session was revoked!"));
+    }
+
+    let access_token = encode_access_token(&_user, &granted, lifetimes.access, issuer.roles, issuer.ring, issuer.config)?;
+
+    Ok(token_response(TokenResponse {
+        access_token,
+        issued_token_type: None,
+        token_type: if session.jkt.is_some() { "DPoP" } else { "Bearer" },
+        expires_in: lifetimes.access,
+        refresh_token: Some(refresh_token),
+        scope: granted.scope,
+        id_token: None,
+    }))
+}
+
+/// Poll of a device for the tokens of its authorization (RFC 8628 section 3.4)
+fn device_code(req: &HttpRequest, form: &TokenForm, client: &OAuthClient, issuer: &Issuer) -> Result<HttpResponse, OAuthError> {
+    let device_hash = match &form.device_code {
+        Some(code) => issuer.hasher.hash(code),
+        None => {
+            return Err(OAuthError::invalid_request("device_code is required!"));
+        }
+    };
+
+    let device = find_device(issuer.mongodb, &device_hash)
+        .filter(|d| d.client_id == client.client_id)
+        .ok_or(OAuthError::invalid_grant("Invalid device code!"))?;
+
+    if device.expires < Utc::now() {
+        let _ = device.delete(issuer.mongodb);
+        return Err(OAuthError::new(StatusCode::BAD_REQUEST, "expired_token", "The device code expired, please start over!"));
+    }
+
+    match device.status {
+        DeviceStatus::Pending => {
+            // Polling faster than the interval raises it by 5 seconds for good (RFC 8628 section 3.5)
+            let too_fast = device.last_polled.map(|last| Utc::now() < last + chrono::Duration::seconds(device.interval)).unwrap_or(false);
+            let interval = if too_fast { device.interval + 5 } else { device.interval };
+
+            let _ = device.polled(issuer.mongodb, interval);
+
+            if too_fast {
+                return Err(OAuthError::new(StatusCode::BAD_REQUEST, "slow_down", "Polling too fast, the interval was raised by 5 seconds!"));
+            }
+            Err(OAuthError::new(StatusCode::BAD_REQUEST, "authorization_pending", "The user hasn't approved the device yet!"))
+        }
+        DeviceStatus::Denied => {
+            let _ = device.delete(issuer.mongodb);
+            Err(OAuthError::new(StatusCode::BAD_REQUEST, "access_denied", "The user denied the device!"))
+        }
+        DeviceStatus::Approved => {
+            // Removed before the tokens are issued, a second poll can't get them again
+            let device = consume_device(issuer.mongodb, &device_hash).ok_or(OAuthError::invalid_grant("Invalid device code!"))?;
+
+            let _user = device.user.and_then(|uuid| find_user(issuer.mongodb, doc! { "_id": uuid_bson(&uuid) }))
+                .ok_or(OAuthError::invalid_grant("User no longer exists!"))?;
+
+            user_tokens(req, client, issuer, &_user, device.scope, device.auth_time.unwrap_or_else(Utc::now), None)
+        }
+    }
+}
+
+/// Issue an access token to a confidential client acting on its own behalf (RFC 6749 section 4.4)
+///
+/// Scopes and audiences default to the registered ones, no refresh token is issued.
+fn client_credentials(form: &TokenForm, client: &OAuthClient, issuer: &Issuer) -> Result<HttpResponse, OAuthError> {
+    if client.is_public() {
+        return Err(OAuthError::new(StatusCode::BAD_REQUEST, "unauthorized_client", "Public clients can't use the client_credentials grant!"));
+    }
+
+    let scope = match form.scope.as_deref() {
+        Some(scope) if !client.scopes_allowed(scope) => {
+            return Err(OAuthError::new(StatusCode::BAD_REQUEST, "invalid_scope", "Scope isn't registered for this client!"));
+        }
+        Some(scope) => scope.split_whitespace().collect::<Vec<&str>>().join(" "),
+        None => client.scopes.join(" "),
+    };
+
+    // Audiences (RFC 8707 section 2) must be registered for the client
+    let audience: Vec<String> = match form.audience.as_deref() {
+        Some(audience) => {
+            let requested: Vec<String> = audience.split_whitespace().map(|a| a.to_string()).collect();
+
+            if requested.iter().any(|a| !client.audiences.contains(a)) {
+                return Err(OAuthError::new(StatusCode::BAD_REQUEST, "invalid_target", "Audience isn't registered for this client!"));
+            }
+            requested
+        }
+        None if client.audiences.is_empty() => issuer.config.audience.clone(),
+        None => client.audiences.clone(),
+    };
+
+    let scope = Some(scope).filter(|s| !s.is_empty());
+    let lifetime = client.lifetimes(issuer.config).access;
+
+    let access_token = encode_service_token(&client.client_id, scope.clone(), audience, issuer.jkt.clone(), lifetime, issuer.ring, issuer.config)?;
+
+    log::info!("Service token issued to client {}", client.client_id);
+
+    Ok(token_response(TokenResponse {
+        access_token,
+        issued_token_type: None,
+        token_type: issuer.token_type(),
+        expires_in: lifetime,
+        refresh_token: None,
+        scope,
+        id_token: None,
+    }))
+}
+
+/// Trade a subject token for a narrower one with another audience, acting on behalf of its subject (RFC 8693)
+///
+/// The exchange policy decides which audiences and scopes the client may ask for.
+fn token_exchange(req: &HttpRequest, form: &TokenForm, client: &OAuthClient, issuer: &Issuer) -> Result<HttpResponse, OAuthError> {
+    if client.is_public() {
+        return Err(OAuthError::new(StatusCode::BAD_REQUEST, "unauthorized_client", "Public clients can't exchange tokens!"));
+    }
+
+    let default = ExchangeConfig::default();
+    let exchange = req.app_data::<web::Data<ExchangeConfig>>().map(|e| e.get_ref()).unwrap_or(&default);
+
+    let subject_token = match (&form.subject_token, form.subject_token_type.as_deref()) {
+        (Some(token), Some(ACCESS_TOKEN_TYPE)) => token,
+        (Some(_), Some(_)) => {
+            return Err(OAuthError::invalid_request("Only access tokens can be exchanged!"));
+        }
+        _ => {
+            return Err(OAuthError::invalid_request("subject_token and subject_token_type are required!"));
+        }
+    };
+
+    if form.actor_token.is_some() {
+        return Err(OAuthError::invalid_request("actor_token isn't supported, the authenticated client is the actor!"));
+    }
+    if form.requested_token_type.as_deref().map(|t| t != ACCESS_TOKEN_TYPE).unwrap_or(false) {
+        return Err(OAuthError::invalid_request("Only access tokens can be requested!"));
+    }
+
+    let audience = form.audience.as_deref().map(|a| a.trim()).filter(|a| !a.is_empty() && !a.contains(' '))
+        .ok_or(OAuthError::invalid_request("A single audience is required!"))?;
+
+    let policy = exchange.policy(&client.client_id, audience)
+        .ok_or(OAuthError::new(StatusCode::BAD_REQUEST, "invalid_target", "The client may not exchange tokens for this audience!"))?;
+
+    let inspector = Inspector {
+        mongodb: issuer.mongodb,
+        ring: issuer.ring,
+        config: issuer.config,
+        hasher: issuer.hasher,
+        revocations: req.app_data::<web::Data<Revocations>>().map(|r| r.get_ref()),
+    };
+
+    let mut subject = inspector.access(subject_token).ok_or(OAuthError::invalid_grant("Invalid subject token!"))?;
+
+    // The subject token must be meant for the client: issued to it, or naming it among its audiences
+    let recipient = subject.client_id.as_deref() == Some(client.client_id.as_str()) || subject.aud.contains(&client.client_id);
+    if !subject.aud.is_empty() && !recipient {
+        return Err(OAuthError::invalid_grant("The subject token wasn't issued for this client!"));
+    }
+
+    if subject.act.as_ref().map(|a| a.depth()).unwrap_or(0) >= exchange.max_depth {
+        return Err(OAuthError::invalid_grant("The subject token can't be exchanged any further!"));
+    }
+
+    // Scopes must be allowed by the policy and granted to the subject token, one without scopes grants none
+    let scope = match policy.scopes(subject.scope.as_deref(), form.scope.as_deref()) {
+        Some(scopes) => Some(scopes.join(" ")).filter(|s| !s.is_empty()),
+        None => {
+            return Err(OAuthError::new(StatusCode::BAD_REQUEST, "invalid_scope", "Scope isn't allowed for this exchange!"));
+        }
+    };
+
+    // The exchanged token never outlives the subject token
+    let now = Utc::now().timestamp();
+    let exp = subject.exp.min((now + exchange.max_lifetime) as usize);
+
+    // A bound subject token is only exchanged for a token bound to the actor's key, the subject's
+    // binding was for the token presented to the actor
+    if subject.cnf.is_some() && issuer.jkt.is_none() {
+        return Err(OAuthError::invalid_grant("The subject token is bound to a key, a DPoP proof of the client's key is required!"));
+    }
+    subject.cnf = issuer.jkt.clone().map(|jkt| Confirmation { jkt });
+
+    log::info!("Token of {} exchanged by client {} for {}", subject.sub, client.client_id, audience);
+
+    let access_token = encode_exchanged_token(subject, &client.client_id, audience.to_string(), scope.clone(), exp, issuer.ring, issuer.config)?;
+
+    Ok(token_response(TokenResponse {
+        access_token,
+        issued_token_type: Some(ACCESS_TOKEN_TYPE),
+        token_type: issuer.token_type(),
+        expires_in: exp as i64 - now,
+        refresh_token: None,
+        scope,
+        id_token: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header::ContentType, test::{call_and_read_body_json, call_service, init_service, TestRequest}, App};
+    use serde_json::{json, Value};
+
+    use crate::{access::extractor::extract::AccessClaims, api::{oauth::oauth, testing}, db::parser::user::DBParser};
+
+    fn exchange() -> ExchangeConfig {
+        serde_json::from_value(json!({ "policies": ["gateway:grades:grades:read+grades:write"] })).unwrap()
+    }
+
+    #[test]
+    fn users_without_permissions_exchange_no_scope() {
+        let user = testing::user(&["student"]);
+        let token = testing::access_token(&user, &testing::session(&user, None, None));
+        let subject = testing::ring().decode::<AccessClaims>(&token, KeyUse::Access, &TokenConfig::default().validation()).unwrap().claims;
+
+        let exchange = exchange();
+        let policy = exchange.policy("gateway", "grades").unwrap();
+
+        assert_eq!(subject.scope, None);
+        assert_eq!(policy.scopes(subject.scope.as_deref(), None), Some(Vec::new()));
+        assert_eq!(policy.scopes(subject.scope.as_deref(), Some("grades:write")), None);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at TEST__MONGO_URL"]
+    async fn exchanges_only_the_permissions_of_the_subject() {
+        let mongodb = testing::mongodb();
+        let user = testing::user(&["student"]);
+        user.insert(&mongodb).unwrap();
+
+        OAuthClient {
+            client_id: "gateway".to_string(),
+            name: None,
+            secret_hash: Some(testing::hasher().hash("secret")),
+            jwks: None,
+            redirect_uris: Vec::new(),
+            grant_types: vec![EXCHANGE_GRANT.to_string()],
+            scopes: Vec::new(),
+            audiences: Vec::new(),
+            client_type: None,
+            access_lifetime: None,
+            refresh_lifetime: None,
+            disabled: false,
+            created: Utc::now(),
+        }.insert(&mongodb).unwrap();
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(mongodb.clone()))
+                .app_data(web::Data::new(testing::ring()))
+                .app_data(web::Data::new(testing::hasher()))
+                .app_data(web::Data::new(TokenConfig::default()))
+                .app_data(web::Data::new(RoleConfig::default()))
+                .app_data(web::Data::new(exchange()))
+                .service(oauth())
+        ).await;
+
+        let subject_token = testing::access_token(&user, &testing::session(&user, None, None));
+        let body = |scope: &str| format!(
+            "grant_type={}&client_id=gateway&client_secret=secret&subject_token={}&subject_token_type={}&audience=grades{}",
+            EXCHANGE_GRANT, subject_token, ACCESS_TOKEN_TYPE, scope
+        );
+
+        let req = |scope: &str| TestRequest::post().uri("/oauth/token").insert_header(ContentType::form_url_encoded()).set_payload(body(scope)).to_request();
+
+        let escalated = call_service(&app, req("&scope=grades:write")).await.status();
+        let exchanged: Value = call_and_read_body_json(&app, req("")).await;
+        testing::drop(&mongodb);
+
+        assert_eq!(escalated, StatusCode::BAD_REQUEST);
+        assert!(exchanged["access_token"].is_string());
+        assert_eq!(exchanged.get("scope"), None);
+    }
+}
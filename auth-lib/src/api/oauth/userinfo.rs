@@ -0,0 +1,38 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use bson::doc;
+use uuid::Uuid;
+
+use crate::{access::{extractor::extract::Token, oidc::UserClaims}, db::{mongo::{MongoDB, uuid_bson}, parser::user::find_user}};
+use super::OAuthError;
+
+/// Error of a protected resource (RFC 6750 section 3), described in the WWW-Authenticate header
+fn bearer_error(status: StatusCode, error: &'static str, description: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .insert_header(("WWW-Authenticate", format!("Bearer error=\"{}\", error_description=\"{}\"", error, description)))
+        .json(OAuthError::new(status, error, description))
+}
+
+/// Claims about the user of an access token with the `openid` scope, according to its other scopes
+pub async fn userinfo(token: Option<Token>, mongodb: web::Data<MongoDB>) -> HttpResponse {
+    let token = match token {
+        Some(t) => t,
+        None => {
+            return bearer_error(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid or missing access token!");
+        }
+    };
+
+    if !token.scopes.iter().any(|s| s == "openid") {
+        return bearer_error(StatusCode::FORBIDDEN, "insufficient_scope", "The openid scope is required!");
+    }
+
+    let _user = match Uuid::parse_str(&token.sub).ok().and_then(|uuid| find_user(&mongodb, doc! { "_id": uuid_bson(&uuid) })) {
+        Some(u) => u,
+        None => {
+            return bearer_error(StatusCode::UNAUTHORIZED, "invalid_token", "User no longer exists!");
+        }
+    };
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(UserClaims::new(&_user, &token.scopes))
+}
@@ -0,0 +1,310 @@
+use actix_web::{Scope, web, HttpResponse, HttpRequest};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{db::{mongo::MongoDB, parser::{client::find_client, session::{Session, Origin, find_session, find_sessions, delete_sessions}, user::User}}, access::{extractor::extract::Token, keyring::KeyRing, keys::KeyError, roles::RoleConfig, tokenize::parser::{encode_access_token, encode_refresh_token}, tokens::{Lifetimes, TokenConfig, TokenHasher}, revocation::Revocations}};
+
+// Headers set by proxies and CDNs with the client's country or region
+const LOCATION_HEADERS: [&str; 3] = ["CF-IPCountry", "CloudFront-Viewer-Country", "X-Client-Location"];
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    message: String,
+}
+
+/// Session as shown to its user, without the refresh token hash
+#[derive(Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub device: Option<String>,
+    pub ip: Option<String>,
+    pub location: Option<String>,
+    pub created: chrono::DateTime<Utc>,
+    pub last_used: chrono::DateTime<Utc>,
+    pub expires: chrono::DateTime<Utc>,
+    // Whether the session is the one of the access token making the request
+    pub current: bool,
+}
+
+impl SessionInfo {
+    pub fn new(session: Session, current: Option<&str>) -> Self {
+        SessionInfo {
+            current: current == Some(session.uuid.to_string().as_str()),
+            id: session.uuid.to_string(),
+            device: session.origin.device,
+            ip: session.origin.ip,
+            location: session.origin.location,
+            created: session.created,
+            last_used: session.last_used,
+            expires: session.expires,
+        }
+    }
+}
+
+/// Read where the request comes from, the device label falls back to the User-Agent
+pub fn origin(req: &HttpRequest, device: Option<String>) -> Origin {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+
+    Origin {
+        device: device.or_else(|| header("User-Agent")),
+        ip: req.connection_info().realip_remote_addr().map(|ip| ip.to_string()),
+        location: LOCATION_HEADERS.iter().find_map(|name| header(name)),
+    }
+}
+
+/// Tokens issued for a new session
+pub struct Issued {
+    pub session: Uuid,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub lifetimes: Lifetimes,
+}
+
+/// Token lifetimes of a session: those of its client type for first-party logins, of the registered client otherwise
+pub fn session_lifetimes(mongodb: &MongoDB, config: &TokenConfig, session: &Session) -> Lifetimes {
+    if session.first_party {
+        return config.lifetimes(session.client.as_deref());
+    }
+
+    match session.client.as_deref().and_then(|id| find_client(mongodb, id)) {
+        Some(c) => c.lifetimes(config),
+        None => config.lifetimes(None),
+    }
+}
+
+/// Why a session could not be opened
+#[derive(Debug)]
+pub enum IssueError {
+    // The tokens could not be signed, e.g. without an active key
+    Key(KeyError),
+    Database(mongodb::error::Error),
+}
+
+impl From<KeyError> for IssueError {
+    fn from(err: KeyError) -> Self {
+        IssueError::Key(err)
+    }
+}
+
+impl From<mongodb::error::Error> for IssueError {
+    fn from(err: mongodb::error::Error) -> Self {
+        IssueError::Database(err)
+    }
+}
+
+/// Keys, settings and stores needed to open sessions
+pub struct Issuer<'a> {
+    pub mongodb: &'a MongoDB,
+    pub ring: &'a KeyRing,
+    pub config: &'a TokenConfig,
+    pub hasher: &'a TokenHasher,
+    pub roles: &'a RoleConfig,
+    // Thumbprint of the DPoP key of the request, the issued tokens are bound to it
+    pub jkt: Option<String>,
+}
+
+impl Issuer<'_> {
+    /// Bound tokens are used with the DPoP scheme instead of Bearer
+    pub fn token_type(&self) -> &'static str {
+        if self.jkt.is_some() { "DPoP" } else { "Bearer" }
+    }
+
+    /// Open a new session for the user with its own refresh token, and issue an access token for it
+    ///
+    /// First-party sessions are logins to the service itself, `client` is then the client type and `scope` is None.
+    pub fn open_session(&self, user: &User, origin: Origin, client: Option<String>, scope: Option<String>, lifetimes: Lifetimes, first_party: bool) -> Result<Issued, IssueError> {
+        let expires = Utc::now() + chrono::Duration::seconds(lifetimes.refresh);
+
+        let sid = Uuid::new_v4();
+        let refresh_token = encode_refresh_token(&user.uuid, user.username.clone(), &sid.to_string(), lifetimes.refresh, self.ring, self.config)?;
+
+        let mut session = Session::new(sid, user.uuid, origin, client, scope, self.hasher.hash(&refresh_token), expires);
+        session.jkt = self.jkt.clone();
+        session.first_party = first_party;
+        session.insert(self.mongodb)?;
+
+        let access_token = encode_access_token(user, &session, lifetimes.access, self.roles, self.ring, self.config)?;
+
+        Ok(Issued {
+            session: sid,
+            access_token,
+            refresh_token,
+            lifetimes,
+        })
+    }
+}
+
+/// Sessions of the user of the access token, nested in the `/user` scope
+pub fn sessions() -> Scope {
+    web::scope("/sessions")
+        .route("", web::get().to(list_sessions))
+        .route("", web::delete().to(revoke_other_sessions))
+        .route("/{id}", web::delete().to(revoke_session))
+}
+
+/// Sessions of a user which haven't expired
+pub fn list(mongodb: &MongoDB, user: &Uuid, current: Option<&str>) -> Vec<SessionInfo> {
+    find_sessions(mongodb, user)
+        .into_iter()
+        .map(|s| SessionInfo::new(s, current))
+        .collect()
+}
+
+/// Revoke the access tokens still outstanding for ended sessions, when a revocation list is registered
+pub fn revoke_tokens(req: &HttpRequest, mongodb: &MongoDB, sessions: &[Session]) {
+    let revocations = match req.app_data::<web::Data<Revocations>>() {
+        Some(r) => r,
+        None => return,
+    };
+
+    let default = TokenConfig::default();
+    let config = req.app_data::<web::Data<TokenConfig>>().map(|c| c.get_ref()).unwrap_or(&default);
+
+    for session in sessions {
+        let lifetime = session_lifetimes(mongodb, config, session).access + config.leeway as i64;
+        revocations.revoke_session(&session.uuid.to_string(), lifetime);
+    }
+}
+
+/// Revoke a session and its access tokens, only if it belongs to the given user
+pub fn revoke(req: &HttpRequest, mongodb: &MongoDB, user: &Uuid, id: &str) -> HttpResponse {
+    let _session = match Uuid::parse_str(id).ok().and_then(|sid| find_session(mongodb, &sid)) {
+        Some(s) if s.user == *user => s,
+        _ => {
+            return HttpResponse::NotFound().json(Response { message: String::from("Session not found!") });
+        }
+    };
+
+    match _session.delete(mongodb) {
+        Ok(_) => {
+            revoke_tokens(req, mongodb, &[_session]);
+            HttpResponse::Ok().json(Response { message: String::from("Session revoked.") })
+        }
+        Err(_) => HttpResponse::BadRequest().json(Response { message: String::from("Error while revoking the session in Database") }),
+    }
+}
+
+/// Revoke every session of the user and their access tokens, except the given session
+pub fn revoke_all(req: &HttpRequest, mongodb: &MongoDB, user: &Uuid, except: Option<&Uuid>) -> HttpResponse {
+    match delete_sessions(mongodb, user, except) {
+        Ok(sessions) => {
+            revoke_tokens(req, mongodb, &sessions);
+            HttpResponse::Ok().json(Response { message: format!("{} sessions revoked.", sessions.len()) })
+        }
+        Err(_) => HttpResponse::BadRequest().json(Response { message: String::from("Error while revoking the sessions in Database") }),
+    }
+}
+
+fn user_of(token: &Token) -> Option<Uuid> {
+    Uuid::parse_str(&token.sub).ok()
+}
+
+/// Only tokens of a first-party login manage the user's sessions, not those of OAuth clients nor exchanged ones
+pub fn not_first_party() -> HttpResponse {
+    HttpResponse::Forbidden().json(Response { message: String::from("Only tokens of a first-party login can manage sessions!") })
+}
+
+async fn list_sessions(token: Token, mongodb: web::Data<MongoDB>) -> HttpResponse {
+    if !token.is_first_party() {
+        return not_first_party();
+    }
+
+    match user_of(&token) {
+        Some(user) => HttpResponse::Ok().json(list(&mongodb, &user, Some(&token.sid))),
+        None => HttpResponse::BadRequest().json(Response { message: String::from("Token doesn't name a user!") }),
+    }
+}
+
+async fn revoke_session(req: HttpRequest, token: Token, mongodb: web::Data<MongoDB>, path: web::Path<String>) -> HttpResponse {
+    if !token.is_first_party() {
+        return not_first_party();
+    }
+
+    match user_of(&token) {
+        Some(user) => revoke(&req, &mongodb, &user, &path.into_inner()),
+        None => HttpResponse::BadRequest().json(Response { message: String::from("Token doesn't name a user!") }),
+    }
+}
+
+/// Sign out every other device, keeping the session of the access token
+async fn revoke_other_sessions(req: HttpRequest, token: Token, mongodb: web::Data<MongoDB>) -> HttpResponse {
+    if !token.is_first_party() {
+        return not_first_party();
+    }
+
+    let current = Uuid::parse_str(&token.sid).ok();
+
+    match user_of(&token) {
+        Some(user) => revoke_all(&req, &mongodb, &user, current.as_ref()),
+        None => HttpResponse::BadRequest().json(Response { message: String::from("Token doesn't name a user!") }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::{Method, StatusCode}, test, App};
+    use serde_json::Value;
+
+    use crate::api::testing;
+
+    async fn call(mongodb: &MongoDB, method: Method, uri: &str, token: String) -> (StatusCode, Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(mongodb.clone()))
+                .app_data(web::Data::new(testing::ring()))
+                .app_data(web::Data::new(TokenConfig::default()))
+                .service(web::scope("/user").service(sessions()))
+        ).await;
+
+        let req = test::TestRequest::default().method(method).uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+
+        let res = test::call_service(&app, req).await;
+        let status = res.status();
+
+        (status, serde_json::from_slice(&test::read_body(res).await).unwrap_or(Value::Null))
+    }
+
+    #[actix_web::test]
+    async fn refuses_tokens_of_clients_and_exchanges() {
+        let mongodb = testing::mongodb();
+        let user = testing::user(&[]);
+        let first_party = testing::session(&user, None, None);
+
+        let tokens = [
+            testing::access_token(&user, &testing::session(&user, Some("lms"), Some("openid"))),
+            testing::exchanged_token(&user, &first_party),
+        ];
+
+        for token in tokens {
+            assert_eq!(call(&mongodb, Method::GET, "/user/sessions", token.clone()).await.0, StatusCode::FORBIDDEN);
+            assert_eq!(call(&mongodb, Method::DELETE, "/user/sessions", token.clone()).await.0, StatusCode::FORBIDDEN);
+            assert_eq!(call(&mongodb, Method::DELETE, &format!("/user/sessions/{}", first_party.uuid), token).await.0, StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at TEST__MONGO_URL"]
+    async fn lists_and_ends_the_sessions_of_a_first_party_login() {
+        let mongodb = testing::mongodb();
+        let user = testing::user(&[]);
+        let current = testing::session(&user, None, None);
+        let other = testing::session(&user, None, None);
+        current.insert(&mongodb).unwrap();
+        other.insert(&mongodb).unwrap();
+
+        let token = testing::access_token(&user, &current);
+        let (listed, sessions) = call(&mongodb, Method::GET, "/user/sessions", token.clone()).await;
+        let (revoked, _) = call(&mongodb, Method::DELETE, "/user/sessions", token).await;
+        let remaining = find_sessions(&mongodb, &user.uuid);
+        testing::drop(&mongodb);
+
+        assert_eq!(listed, StatusCode::OK);
+        assert_eq!(sessions.as_array().map(|s| s.len()), Some(2));
+        assert_eq!(revoked, StatusCode::OK);
+        assert_eq!(remaining.iter().map(|s| s.uuid).collect::<Vec<Uuid>>(), vec![current.uuid]);
+    }
+}
@@ -0,0 +1,96 @@
+use chrono::Utc;
+use mongodb::sync::Client;
+use uuid::Uuid;
+
+use crate::{access::{extractor::extract::AccessClaims, keyring::{KeyRing, KeyUse}, keys::KeyConfig, roles::RoleConfig, tokenize::{format::Format, parser::{encode_access_token, encode_exchanged_token}}, tokens::{Secret, TokenConfig, TokenHasher}}, db::{mongo::{Database, MongoDB}, parser::{session::{Origin, Session}, user::User}}};
+
+// Fixtures shared by the handler tests. Tests which reach the database are ignored by default,
+// they run against a disposable database of the server at TEST__MONGO_URL with `cargo test -- --ignored`.
+
+pub fn secret() -> Secret {
+    Secret {
+        refresh: "refresh".to_string(),
+        access: "access".to_string(),
+        salt: "salt".to_string(),
+        admin: None,
+        hash: "hash".to_string(),
+    }
+}
+
+pub fn ring() -> KeyRing {
+    let config = KeyConfig {
+        algorithm: jsonwebtoken::Algorithm::HS256,
+        format: Format::Jwt,
+        private_key: None,
+        public_key: None,
+        ring: None,
+        jwks_max_age: 3600,
+    };
+
+    KeyRing::load(&config, &secret()).unwrap()
+}
+
+pub fn hasher() -> TokenHasher {
+    TokenHasher::new(&secret())
+}
+
+/// Database of its own for each test, no connection is made before the first query
+pub fn mongodb() -> MongoDB {
+    let url = std::env::var("TEST__MONGO_URL").unwrap_or("mongodb://127.0.0.1:27017".to_string());
+
+    MongoDB {
+        client: Client::with_uri_str(&url).unwrap(),
+        database: Database {
+            url,
+            name: format!("auth-test-{}", Uuid::new_v4().simple()),
+            collection: "users".to_string(),
+            sessions: "sessions".to_string(),
+            revocations: "revocations".to_string(),
+            codes: "codes".to_string(),
+            devices: "devices".to_string(),
+            clients: "clients".to_string(),
+            audit: "audit".to_string(),
+        },
+    }
+}
+
+/// Remove the database of a test
+pub fn drop(mongodb: &MongoDB) {
+    let _ = mongodb.client.database(&mongodb.database.name).drop(None);
+}
+
+pub fn user(roles: &[&str]) -> User {
+    User {
+        uuid: Uuid::new_v4(),
+        username: format!("user-{}", Uuid::new_v4().simple()),
+        email: "user@example.com".to_string(),
+        password_hash: String::new(),
+        name: None,
+        refresh_token: None,
+        refresh_hash: None,
+        photo_url: None,
+        roles: roles.iter().map(|r| r.to_string()).collect(),
+        permissions: Vec::new(),
+        refresh_creation: Utc::now(),
+    }
+}
+
+/// First-party session when no client is given, otherwise a session opened for that OAuth client
+pub fn session(user: &User, client: Option<&str>, scope: Option<&str>) -> Session {
+    let mut session = Session::new(Uuid::new_v4(), user.uuid, Origin::default(), client.map(|c| c.to_string()), scope.map(|s| s.to_string()), String::new(), Utc::now() + chrono::Duration::hours(1));
+    session.first_party = client.is_none();
+    session
+}
+
+pub fn access_token(user: &User, session: &Session) -> String {
+    encode_access_token(user, session, 300, &RoleConfig::default(), &ring(), &TokenConfig::default()).unwrap()
+}
+
+/// Token of the user's session exchanged by the `reports` service
+pub fn exchanged_token(user: &User, session: &Session) -> String {
+    let config = TokenConfig::default();
+    let subject = ring().decode::<AccessClaims>(&access_token(user, session), KeyUse::Access, &config.validation()).unwrap().claims;
+    let exp = subject.exp;
+
+    encode_exchanged_token(subject, "reports", "grades".to_string(), None, exp, &ring(), &config).unwrap()
+}
@@ -1,7 +1,9 @@
-use bson::Document;
+use bson::{spec::BinarySubtype, Binary, Bson, Document};
 use serde::Deserialize;
 use mongodb::{bson::doc, options::{ClientOptions, ServerApi, ServerApiVersion}, sync::Client};
 
+use uuid::Uuid;
+
 use super::parser::user::User;
 extern crate env_logger;
 
@@ -17,7 +19,50 @@ pub struct MongoDB {
 pub struct Database {
     pub url: String,
     pub name: String,
+    // Collection of users
     pub collection: String,
+    // Collection of signed-in sessions
+    #[serde(default = "default_sessions")]
+    pub sessions: String,
+    // Collection of revoked access tokens, when they are shared between nodes
+    #[serde(default = "default_revocations")]
+    pub revocations: String,
+    // Collection of pending authorization codes
+    #[serde(default = "default_codes")]
+    pub codes: String,
+    // Collection of pending device authorizations
+    #[serde(default = "default_devices")]
+    pub devices: String,
+    // Collection of registered OAuth clients
+    #[serde(default = "default_clients")]
+    pub clients: String,
+    // Collection of security audit events
+    #[serde(default = "default_audit")]
+    pub audit: String,
+}
+
+fn default_sessions() -> String {
+    "sessions".to_string()
+}
+
+fn default_revocations() -> String {
+    "revocations".to_string()
+}
+
+fn default_codes() -> String {
+    "codes".to_string()
+}
+
+fn default_devices() -> String {
+    "devices".to_string()
+}
+
+fn default_clients() -> String {
+    "clients".to_string()
+}
+
+fn default_audit() -> String {
+    "audit".to_string()
 }
 
 /// Connect to MongoDB cluster based on given url
@@ -40,6 +85,16 @@ pub fn connect_mongo(url: &String) -> mongodb::error::Result<Client> {
     Ok(client)
 }
 
+/// Uuid as written by the collections' serializer, a generic binary, to be used in filters
+///
+/// `Bson::from(Uuid)` is a binary of the uuid subtype and never matches a stored document.
+pub fn uuid_bson(uuid: &Uuid) -> Bson {
+    Bson::Binary(Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: uuid.as_bytes().to_vec(),
+    })
+}
+
 /// Wrapper for filter to query in Database for minimum an instance of desired document
 pub fn find_one(_mongodb: &MongoDB, filter: impl Into<Option<Document>>) -> bool {
     let _coll = _mongodb.client.database(&_mongodb.database.name).collection::<User>(&_mongodb.database.collection);
@@ -0,0 +1,52 @@
+extern crate chrono;
+
+use bson::Document;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use mongodb::results::InsertOneResult;
+use uuid::Uuid;
+
+use crate::db::mongo::MongoDB;
+
+/// Security relevant event, kept for later investigation
+#[derive(Deserialize, Serialize, Debug)]
+pub struct AuditEvent {
+    #[serde(rename = "_id")]
+    pub uuid: Uuid,
+
+    // Kind of event, e.g. `refresh_token_reuse`
+    pub event: String,
+    pub user: Option<Uuid>,
+    pub ip: Option<String>,
+    pub details: Document,
+
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created: chrono::DateTime<Utc>,
+}
+
+impl AuditEvent {
+    pub fn new(event: &str, user: Option<Uuid>, ip: Option<String>, details: Document) -> Self {
+        AuditEvent {
+            uuid: Uuid::new_v4(),
+            event: event.to_string(),
+            user,
+            ip,
+            details,
+            created: Utc::now(),
+        }
+    }
+
+    pub fn insert(&self, mongodb: &MongoDB) -> Result<InsertOneResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<AuditEvent>(&mongodb.database.audit);
+        _coll.insert_one(self, None)
+    }
+
+    /// Write the event, only logging if the database refuses it
+    pub fn record(&self, mongodb: &MongoDB) {
+        log::warn!("Audit event {}: user {:?}, details {}", self.event, self.user, self.details);
+
+        if let Err(err) = self.insert(mongodb) {
+            log::error!("Could not write audit event {}: {}", self.event, err);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+extern crate chrono;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use mongodb::{bson::doc, options::UpdateOptions, results::{InsertOneResult, UpdateResult}};
+
+use crate::{access::{exchange::EXCHANGE_GRANT, oidc::OIDC_SCOPES, tokens::{Lifetimes, TokenConfig}}, db::{mongo::MongoDB, parser::device::DEVICE_GRANT}};
+
+// Grant types a client may be registered for
+pub const GRANT_TYPES: [&str; 5] = ["authorization_code", "refresh_token", "client_credentials", DEVICE_GRANT, EXCHANGE_GRANT];
+
+/// Application registered to use the OAuth endpoints
+///
+/// Clients without a secret or keys are public clients, e.g. single page or mobile apps.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OAuthClient {
+    #[serde(rename = "_id")]
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    // Keyed hash of the secret for `client_secret_basic` and `client_secret_post`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_hash: Option<String>,
+    // JSON Web Key Set verifying the assertions of `private_key_jwt`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks: Option<String>,
+
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub grant_types: Vec<String>,
+    // Scopes the client may request, none when empty
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    // Audiences the client may request for its service tokens, the TOKEN__AUDIENCE when empty
+    #[serde(default)]
+    pub audiences: Vec<String>,
+
+    // Client type of TOKEN__CLIENT_LIFETIMES whose lifetimes apply when the client doesn't set its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_type: Option<String>,
+    // Token lifetimes in seconds, those of the client type or the defaults of TOKEN__ when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_lifetime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_lifetime: Option<i64>,
+
+    #[serde(default)]
+    pub disabled: bool,
+
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created: chrono::DateTime<Utc>,
+}
+
+/// Find the client with the given id
+pub fn find_client(mongodb: &MongoDB, client_id: &str) -> Option<OAuthClient> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<OAuthClient>(&mongodb.database.clients);
+
+    _coll.find_one(doc! { "_id": client_id }, None).unwrap_or(None)
+}
+
+pub fn find_clients(mongodb: &MongoDB) -> Vec<OAuthClient> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<OAuthClient>(&mongodb.database.clients);
+
+    match _coll.find(None, None) {
+        Ok(cursor) => cursor.filter_map(|c| c.ok()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+impl OAuthClient {
+    pub fn is_public(&self) -> bool {
+        self.secret_hash.is_none() && self.jwks.is_none()
+    }
+
+    pub fn allows_grant(&self, grant_type: &str) -> bool {
+        self.grant_types.iter().any(|g| g == grant_type)
+    }
+
+    /// Redirect URIs are compared exactly, without any normalization
+    pub fn redirect_allowed(&self, redirect_uri: &str) -> bool {
+        self.redirect_uris.iter().any(|u| u == redirect_uri)
+    }
+
+    /// Whether every space separated scope was registered for the client
+    pub fn scopes_allowed(&self, scope: &str) -> bool {
+        scope.split_whitespace().all(|s| self.scopes.iter().any(|allowed| allowed == s))
+    }
+
+    /// Whether every scope a user may grant to the client was registered
+    ///
+    /// The OpenID Connect scopes always are when the service can issue ID tokens.
+    pub fn user_scopes_allowed(&self, scope: &str, oidc: bool) -> bool {
+        scope.split_whitespace().all(|s| (oidc && OIDC_SCOPES.contains(&s)) || self.scopes.iter().any(|allowed| allowed == s))
+    }
+
+    /// Lifetimes of the client, falling back to those of its client type
+    pub fn lifetimes(&self, config: &TokenConfig) -> Lifetimes {
+        let defaults = config.lifetimes(self.client_type.as_deref());
+
+        Lifetimes {
+            access: self.access_lifetime.unwrap_or(defaults.access),
+            refresh: self.refresh_lifetime.unwrap_or(defaults.refresh),
+        }
+    }
+
+    pub fn insert(&self, mongodb: &MongoDB) -> Result<InsertOneResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<OAuthClient>(&mongodb.database.clients);
+        _coll.insert_one(self, None)
+    }
+
+    /// Register a client of the environment, or bring an existing one in line with it
+    ///
+    /// The secret and redirect URIs are taken from the environment and its grant types added, the
+    /// other settings made through the admin endpoints, e.g. disabling the client, are kept.
+    pub fn seed(&self, mongodb: &MongoDB) -> Result<UpdateResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<OAuthClient>(&mongodb.database.clients);
+
+        let filter = doc! {
+            "_id": self.client_id.clone()
+        };
+
+        let mut set = doc! {
+            "redirect_uris": self.redirect_uris.clone()
+        };
+        if let Some(hash) = &self.secret_hash {
+            set.insert("secret_hash", hash.clone());
+        }
+
+        let update = doc! {
+            "$set": set,
+            "$addToSet": {
+                "grant_types": { "$each": self.grant_types.clone() }
+            },
+            "$setOnInsert": {
+                "scopes": self.scopes.clone(),
+                "audiences": self.audiences.clone(),
+                "disabled": self.disabled,
+                "created": bson::DateTime::from_chrono(self.created)
+            }
+        };
+
+        _coll.update_one(filter, update, UpdateOptions::builder().upsert(true).build())
+    }
+
+    pub fn update_secret(&self, mongodb: &MongoDB, secret_hash: String) -> Result<UpdateResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<OAuthClient>(&mongodb.database.clients);
+
+        let filter = doc! {
+            "_id": self.client_id.clone()
+        };
+
+        let update = doc! {
+            "$set": {
+                "secret_hash": secret_hash
+            }
+        };
+
+        _coll.update_one(filter, update, None)
+    }
+
+    pub fn update_disabled(&self, mongodb: &MongoDB, disabled: bool) -> Result<UpdateResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<OAuthClient>(&mongodb.database.clients);
+
+        let filter = doc! {
+            "_id": self.client_id.clone()
+        };
+
+        let update = doc! {
+            "$set": {
+                "disabled": disabled
+            }
+        };
+
+        _coll.update_one(filter, update, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::tokens::ClientLifetimes;
+
+    fn client(client_id: &str, client_type: Option<&str>, access_lifetime: Option<i64>) -> OAuthClient {
+        OAuthClient {
+            client_id: client_id.to_string(),
+            name: None,
+            secret_hash: None,
+            jwks: None,
+            redirect_uris: Vec::new(),
+            grant_types: Vec::new(),
+            scopes: Vec::new(),
+            audiences: Vec::new(),
+            client_type: client_type.map(|t| t.to_string()),
+            access_lifetime,
+            refresh_lifetime: None,
+            disabled: false,
+            created: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn takes_lifetimes_from_the_client_type() {
+        let mut config = TokenConfig::default();
+        config.client_lifetimes.insert("mobile".to_string(), ClientLifetimes { access: Some(60), refresh: Some(2592000) });
+
+        // A client named like a client type doesn't get its lifetimes
+        let named = client("mobile", None, None).lifetimes(&config);
+        assert_eq!((named.access, named.refresh), (config.access_lifetime, config.refresh_lifetime));
+
+        let typed = client("library", Some("mobile"), Some(120)).lifetimes(&config);
+        assert_eq!((typed.access, typed.refresh), (120, 2592000));
+    }
+}
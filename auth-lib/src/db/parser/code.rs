@@ -0,0 +1,47 @@
+extern crate chrono;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use mongodb::{bson::doc, results::InsertOneResult};
+use uuid::Uuid;
+
+use crate::db::mongo::MongoDB;
+
+/// Authorization code of the OAuth code flow, stored under the hash of the code
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuthorizationCode {
+    #[serde(rename = "_id")]
+    pub code_hash: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub user: Uuid,
+
+    // PKCE challenge, the S256 hash of the client's verifier
+    pub code_challenge: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    // OpenID Connect nonce, repeated in the ID token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+
+    // When the user signed in
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub auth_time: chrono::DateTime<Utc>,
+
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires: chrono::DateTime<Utc>,
+}
+
+impl AuthorizationCode {
+    pub fn insert(&self, mongodb: &MongoDB) -> Result<InsertOneResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<AuthorizationCode>(&mongodb.database.codes);
+        _coll.insert_one(self, None)
+    }
+}
+
+/// Take the code out of the store, so it can only be exchanged once
+pub fn consume_code(mongodb: &MongoDB, code_hash: &str) -> Option<AuthorizationCode> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<AuthorizationCode>(&mongodb.database.codes);
+
+    _coll.find_one_and_delete(doc! { "_id": code_hash }, None).unwrap_or(None)
+}
@@ -0,0 +1,136 @@
+extern crate chrono;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use mongodb::{bson::doc, options::IndexOptions, results::{CreateIndexesResult, DeleteResult, InsertOneResult, UpdateResult}, IndexModel};
+use uuid::Uuid;
+
+use crate::db::mongo::{MongoDB, uuid_bson};
+
+// Grant type of the token requests polled by devices
+pub const DEVICE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Decision of the user on a device authorization
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+/// Device authorization request (RFC 8628), stored under its user code
+///
+/// Only the keyed hash of the device code is stored, the device polls the token endpoint with it.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DeviceAuthorization {
+    #[serde(rename = "_id")]
+    pub user_code: String,
+    pub device_hash: String,
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+
+    pub status: DeviceStatus,
+    // User who approved the request, and when they signed in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub auth_time: Option<chrono::DateTime<Utc>>,
+
+    // Seconds the device must wait between polls, raised when it polls too fast
+    pub interval: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional")]
+    pub last_polled: Option<chrono::DateTime<Utc>>,
+
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires: chrono::DateTime<Utc>,
+}
+
+/// Index the requests by device code, and let the database evict them once expired
+///
+/// The TTL monitor only runs every minute, expired requests are still refused by their `expires`.
+pub fn create_device_indexes(mongodb: &MongoDB) -> Result<CreateIndexesResult, mongodb::error::Error> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<DeviceAuthorization>(&mongodb.database.devices);
+
+    let indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "expires": 1 })
+            .options(IndexOptions::builder().expire_after(std::time::Duration::ZERO).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "device_hash": 1 })
+            .build(),
+    ];
+
+    _coll.create_indexes(indexes, None)
+}
+
+/// Find the request shown to the user with the given code
+pub fn find_user_code(mongodb: &MongoDB, user_code: &str) -> Option<DeviceAuthorization> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<DeviceAuthorization>(&mongodb.database.devices);
+
+    _coll.find_one(doc! { "_id": user_code }, None).unwrap_or(None)
+}
+
+/// Find the request polled for with the given device code hash
+pub fn find_device(mongodb: &MongoDB, device_hash: &str) -> Option<DeviceAuthorization> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<DeviceAuthorization>(&mongodb.database.devices);
+
+    _coll.find_one(doc! { "device_hash": device_hash }, None).unwrap_or(None)
+}
+
+impl DeviceAuthorization {
+    pub fn insert(&self, mongodb: &MongoDB) -> Result<InsertOneResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<DeviceAuthorization>(&mongodb.database.devices);
+        _coll.insert_one(self, None)
+    }
+
+    pub fn delete(&self, mongodb: &MongoDB) -> Result<DeleteResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<DeviceAuthorization>(&mongodb.database.devices);
+        _coll.delete_one(doc! { "_id": self.user_code.clone() }, None)
+    }
+
+    /// Approve the request for the user, or deny it, only while it is pending
+    pub fn decide(&self, mongodb: &MongoDB, user: &Uuid, approved: bool) -> Result<UpdateResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<DeviceAuthorization>(&mongodb.database.devices);
+
+        let filter = doc! {
+            "_id": self.user_code.clone(),
+            "status": "pending"
+        };
+
+        let status = if approved { "approved" } else { "denied" };
+
+        let update = doc! {
+            "$set": {
+                "status": status,
+                "user": uuid_bson(user),
+                "auth_time": bson::DateTime::from_chrono(Utc::now())
+            }
+        };
+
+        _coll.update_one(filter, update, None)
+    }
+
+    /// Record a poll of the device with the interval it must keep from now on
+    pub fn polled(&self, mongodb: &MongoDB, interval: i64) -> Result<UpdateResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<DeviceAuthorization>(&mongodb.database.devices);
+
+        let update = doc! {
+            "$set": {
+                "interval": interval,
+                "last_polled": bson::DateTime::from_chrono(Utc::now())
+            }
+        };
+
+        _coll.update_one(doc! { "_id": self.user_code.clone() }, update, None)
+    }
+}
+
+/// Take an approved request out of the store, so its tokens are only issued once
+pub fn consume_device(mongodb: &MongoDB, device_hash: &str) -> Option<DeviceAuthorization> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<DeviceAuthorization>(&mongodb.database.devices);
+
+    _coll.find_one_and_delete(doc! { "device_hash": device_hash, "status": "approved" }, None).unwrap_or(None)
+}
@@ -1 +1,7 @@
-pub mod user;
\ No newline at end of file
+pub mod user;
+pub mod session;
+pub mod audit;
+pub mod revocation;
+pub mod code;
+pub mod device;
+pub mod client;
\ No newline at end of file
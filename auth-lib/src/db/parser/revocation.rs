@@ -0,0 +1,93 @@
+use chrono::{DateTime, Utc};
+use mongodb::{bson::doc, error::{ErrorKind, WriteFailure}, options::{IndexOptions, UpdateOptions}, IndexModel};
+
+use crate::{access::revocation::{RevocationStore, Revoked}, db::mongo::MongoDB};
+
+// Error code of an insert whose _id is already present
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Revocation list shared by every node through the database
+///
+/// A TTL index on `expires` lets the database evict entries once their token expired.
+pub struct MongoStore {
+    mongodb: MongoDB,
+}
+
+impl MongoStore {
+    pub fn new(mongodb: MongoDB) -> Result<Self, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<Revoked>(&mongodb.database.revocations);
+
+        let index = IndexModel::builder()
+            .keys(doc! { "expires": 1 })
+            .options(IndexOptions::builder().expire_after(std::time::Duration::ZERO).build())
+            .build();
+
+        _coll.create_index(index, None)?;
+
+        Ok(MongoStore { mongodb })
+    }
+
+    fn collection(&self) -> mongodb::sync::Collection<Revoked> {
+        self.mongodb.client.database(&self.mongodb.database.name).collection::<Revoked>(&self.mongodb.database.revocations)
+    }
+}
+
+impl RevocationStore for MongoStore {
+    fn revoke(&self, entry: &Revoked) {
+        let filter = doc! {
+            "_id": entry.jti.clone()
+        };
+
+        let update = doc! {
+            "$set": {
+                "expires": bson::DateTime::from_chrono(entry.expires),
+                "revoked": bson::DateTime::from_chrono(entry.revoked)
+            }
+        };
+
+        if let Err(err) = self.collection().update_one(filter, update, UpdateOptions::builder().upsert(true).build()) {
+            log::warn!("Could not revoke token {}: {}", entry.jti, err);
+        }
+    }
+
+    fn is_revoked(&self, jti: &str) -> bool {
+        // The TTL monitor only runs every minute, expired entries may still be there
+        let filter = doc! {
+            "_id": jti,
+            "expires": { "$gt": bson::DateTime::now() }
+        };
+
+        match self.collection().count_documents(filter, None) {
+            Ok(n) => n > 0,
+            // The token can't be known to be valid, it is refused
+            Err(err) => {
+                log::warn!("Could not check the revocation of token {}: {}", jti, err);
+                true
+            }
+        }
+    }
+
+    fn insert_new(&self, entry: &Revoked) -> bool {
+        // The unique _id makes the insert fail when the id was already used
+        match self.collection().insert_one(entry, None) {
+            Ok(_) => true,
+            Err(err) => {
+                if !matches!(*err.kind, ErrorKind::Write(WriteFailure::WriteError(ref e)) if e.code == DUPLICATE_KEY) {
+                    log::warn!("Could not record the use of {}: {}", entry.jti, err);
+                }
+                false
+            }
+        }
+    }
+
+    fn revoked_since(&self, since: DateTime<Utc>) -> Vec<Revoked> {
+        let filter = doc! {
+            "revoked": { "$gt": bson::DateTime::from_chrono(since) }
+        };
+
+        match self.collection().find(filter, None) {
+            Ok(cursor) => cursor.filter_map(|r| r.ok()).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
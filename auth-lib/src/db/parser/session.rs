@@ -0,0 +1,194 @@
+extern crate chrono;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use mongodb::{bson::{doc, Document}, results::{InsertOneResult, DeleteResult, UpdateResult}};
+use uuid::Uuid;
+
+use crate::db::mongo::{MongoDB, uuid_bson};
+
+/// Signed-in device of a user, created at each login
+///
+/// The session is the family of its rotated refresh tokens, only the keyed hash of the current one is stored.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Session {
+    #[serde(rename = "_id")]
+    pub uuid: Uuid,
+    pub user: Uuid,
+
+    #[serde(flatten)]
+    pub origin: Origin,
+    // Client type selecting the token lifetimes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    // Opened by a login to the service itself rather than for an OAuth client, sessions stored before the marker are not
+    #[serde(default)]
+    pub first_party: bool,
+    // Scopes granted by the user through an OAuth client, carried by every access token of the session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    // Thumbprint of the DPoP key the session's tokens are bound to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jkt: Option<String>,
+
+    pub refresh_hash: String,
+
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created: chrono::DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub last_used: chrono::DateTime<Utc>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires: chrono::DateTime<Utc>,
+}
+
+/// Where a session was opened or last used from
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Origin {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    // Coarse location reported by the proxy in front of the service, e.g. a country code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+/// Find the session with the given id
+pub fn find_session(mongodb: &MongoDB, uuid: &Uuid) -> Option<Session> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<Session>(&mongodb.database.sessions);
+
+    _coll.find_one(doc! { "_id": uuid_bson(uuid) }, None).unwrap_or(None)
+}
+
+/// Find the sessions of a user which haven't expired, most recently used first
+pub fn find_sessions(mongodb: &MongoDB, user: &Uuid) -> Vec<Session> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<Session>(&mongodb.database.sessions);
+
+    let filter = doc! {
+        "user": uuid_bson(user),
+        "expires": { "$gt": bson::DateTime::now() }
+    };
+    let options = mongodb::options::FindOptions::builder().sort(doc! { "last_used": -1 }).build();
+
+    match _coll.find(filter, options) {
+        Ok(cursor) => cursor.filter_map(|s| s.ok()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Delete every session of a user, except the given one, and return the deleted sessions
+///
+/// Each session is deleted and returned by the same operation, so one opened or rotated meanwhile is
+/// either returned, and its tokens revoked by the caller, or left in place.
+pub fn delete_sessions(mongodb: &MongoDB, user: &Uuid, except: Option<&Uuid>) -> Result<Vec<Session>, mongodb::error::Error> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<Session>(&mongodb.database.sessions);
+
+    let mut filter = doc! {
+        "user": uuid_bson(user)
+    };
+
+    if let Some(uuid) = except {
+        filter.insert("_id", doc! { "$ne": uuid_bson(uuid) });
+    }
+
+    let mut sessions = Vec::new();
+    while let Some(session) = _coll.find_one_and_delete(filter.clone(), None)? {
+        sessions.push(session);
+    }
+    Ok(sessions)
+}
+
+impl Session {
+    pub fn new(uuid: Uuid, user: Uuid, origin: Origin, client: Option<String>, scope: Option<String>, refresh_hash: String, expires: chrono::DateTime<Utc>) -> Self {
+        let now = Utc::now();
+
+        Session {
+            uuid,
+            user,
+            origin,
+            client,
+            first_party: false,
+            scope,
+            jkt: None,
+            refresh_hash,
+            created: now,
+            last_used: now,
+            expires,
+        }
+    }
+
+    pub fn insert(&self, mongodb: &MongoDB) -> Result<InsertOneResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<Session>(&mongodb.database.sessions);
+        _coll.insert_one(self, None)
+    }
+
+    pub fn delete(&self, mongodb: &MongoDB) -> Result<DeleteResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<Session>(&mongodb.database.sessions);
+
+        let filter = doc! {
+            "_id": uuid_bson(&self.uuid)
+        };
+
+        _coll.delete_one(filter, None)
+    }
+
+    /// Replace the refresh token hash only if it is still one of the given ones, so a token can't be rotated twice
+    ///
+    /// The address and location are updated to where the session is used from, the device is kept.
+    pub fn rotate(&self, mongodb: &MongoDB, current: &[String], refresh_hash: String, expires: chrono::DateTime<Utc>, origin: &Origin) -> Result<UpdateResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<Session>(&mongodb.database.sessions);
+
+        let filter = doc! {
+            "_id": uuid_bson(&self.uuid),
+            "refresh_hash": { "$in": current }
+        };
+
+        let mut update: Document = doc! {
+            "refresh_hash": refresh_hash,
+            "last_used": bson::DateTime::from_chrono(Utc::now()),
+            "expires": bson::DateTime::from_chrono(expires)
+        };
+
+        if let Some(ip) = &origin.ip {
+            update.insert("ip", ip);
+        }
+        if let Some(location) = &origin.location {
+            update.insert("location", location);
+        }
+
+        let update = doc! { "$set": update };
+
+        _coll.update_one(filter, update, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::testing;
+
+    #[test]
+    #[ignore = "needs MongoDB at TEST__MONGO_URL"]
+    fn deletes_and_returns_the_other_sessions() {
+        let mongodb = testing::mongodb();
+        let user = testing::user(&[]);
+
+        let current = testing::session(&user, None, None);
+        let others = [testing::session(&user, None, None), testing::session(&user, Some("lms"), Some(""))];
+        for session in others.iter().chain([&current]) {
+            session.insert(&mongodb).unwrap();
+        }
+
+        let deleted = delete_sessions(&mongodb, &user.uuid, Some(&current.uuid));
+        let remaining = find_sessions(&mongodb, &user.uuid);
+        testing::drop(&mongodb);
+
+        let mut deleted: Vec<Uuid> = deleted.unwrap().iter().map(|s| s.uuid).collect();
+        let mut expected: Vec<Uuid> = others.iter().map(|s| s.uuid).collect();
+        deleted.sort();
+        expected.sort();
+
+        assert_eq!(deleted, expected);
+        assert_eq!(remaining.iter().map(|s| s.uuid).collect::<Vec<Uuid>>(), vec![current.uuid]);
+    }
+}
@@ -2,10 +2,10 @@ extern crate chrono;
 
 use chrono::Utc;
 use serde::{Deserialize, Serialize, };
-use mongodb::{bson::doc, results::{InsertOneResult, DeleteResult, UpdateResult}};
+use mongodb::{bson::{doc, Document}, results::{InsertOneResult, DeleteResult, UpdateResult}};
 use uuid::Uuid;
 
-use crate::db::mongo::MongoDB;
+use crate::{access::tokens::TokenHasher, db::mongo::{MongoDB, uuid_bson}};
 
 pub trait DBParser {
     fn insert(&self, mongodb: &MongoDB) -> Result<InsertOneResult, mongodb::error::Error>;
@@ -15,6 +15,10 @@ pub trait DBParser {
     fn update_photo(&self, mongodb: &MongoDB, photo_url: String) -> Result<UpdateResult, mongodb::error::Error>;
     fn update_password(&self, mongodb: &MongoDB, password_hash: String) -> Result<UpdateResult, mongodb::error::Error>;
     fn update_token(&self, mongodb: &MongoDB, refresh_token: String) -> Result<UpdateResult, mongodb::error::Error>;
+    fn update_roles(&self, mongodb: &MongoDB, roles: Vec<String>) -> Result<UpdateResult, mongodb::error::Error>;
+    fn update_permissions(&self, mongodb: &MongoDB, permissions: Vec<String>) -> Result<UpdateResult, mongodb::error::Error>;
+
+    fn revoke_token(&self, mongodb: &MongoDB) -> Result<UpdateResult, mongodb::error::Error>;
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -29,15 +33,63 @@ pub struct User {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
-    pub refresh_token: String,
+    // Refresh token issued before sessions were introduced, replaced by its hash at startup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    // Keyed hash of that token, moved to a session on its next refresh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_hash: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub photo_url: Option<String>,
 
+    // Roles assigned by administrators, carried in access tokens
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    // Permissions granted to the user alone, on top of those of its roles
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<String>,
+
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub refresh_creation: chrono::DateTime<Utc>,
 }
 
+/// Find the user matching the filter
+pub fn find_user(mongodb: &MongoDB, filter: Document) -> Option<User> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<User>(&mongodb.database.collection);
+
+    _coll.find_one(filter, None).unwrap_or(None)
+}
+
+/// Replace the plaintext refresh tokens stored on users by their keyed hash, returns how many were replaced
+pub fn migrate_tokens(mongodb: &MongoDB, hasher: &TokenHasher) -> Result<u64, mongodb::error::Error> {
+    let _coll = mongodb.client.database(&mongodb.database.name).collection::<User>(&mongodb.database.collection);
+
+    let filter = doc! {
+        "refresh_token": { "$exists": true }
+    };
+
+    let mut migrated = 0;
+
+    for _user in _coll.find(filter, None)? {
+        let _user = _user?;
+
+        let update = match _user.refresh_token.as_deref() {
+            Some(token) if !token.is_empty() => doc! {
+                "$set": { "refresh_hash": hasher.hash(token) },
+                "$unset": { "refresh_token": "" }
+            },
+            _ => doc! {
+                "$unset": { "refresh_token": "" }
+            },
+        };
+
+        _coll.update_one(doc! { "_id": uuid_bson(&_user.uuid) }, update, None)?;
+        migrated += 1;
+    }
+    Ok(migrated)
+}
+
 impl DBParser for User {
     fn insert(&self, mongodb: &MongoDB) -> Result<InsertOneResult, mongodb::error::Error> {
         let _coll = mongodb.client.database(&mongodb.database.name).collection::<User>(&mongodb.database.collection);
@@ -50,7 +102,7 @@ impl DBParser for User {
         let _uuid = self.uuid;
 
         let filter = doc! {
-            "_id": _uuid
+            "_id": uuid_bson(&_uuid)
         };
 
         _coll.delete_one(filter, None)
@@ -62,7 +114,7 @@ impl DBParser for User {
         let _uuid = self.uuid;
 
         let filter = doc! {
-            "_id": _uuid
+            "_id": uuid_bson(&_uuid)
         };
 
         let update = doc! {
@@ -80,7 +132,7 @@ impl DBParser for User {
         let _uuid = self.uuid;
 
         let filter = doc! {
-            "_id": _uuid
+            "_id": uuid_bson(&_uuid)
         };
 
         let update = doc! {
@@ -98,7 +150,7 @@ impl DBParser for User {
         let _uuid = self.uuid;
 
         let filter = doc! {
-            "_id": _uuid
+            "_id": uuid_bson(&_uuid)
         };
 
         let update = doc! {
@@ -116,7 +168,7 @@ impl DBParser for User {
         let _uuid = self.uuid;
 
         let filter = doc! {
-            "_id": _uuid
+            "_id": uuid_bson(&_uuid)
         };
 
         let update = doc! {
@@ -127,4 +179,60 @@ impl DBParser for User {
 
         _coll.update_one(filter, update, None)
     }
+
+    fn update_roles(&self, mongodb: &MongoDB, roles: Vec<String>) -> Result<UpdateResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<User>(&mongodb.database.collection);
+
+        let _uuid = self.uuid;
+
+        let filter = doc! {
+            "_id": uuid_bson(&_uuid)
+        };
+
+        let update = doc! {
+            "$set": {
+                "roles": roles
+            }
+        };
+
+        _coll.update_one(filter, update, None)
+    }
+
+    fn update_permissions(&self, mongodb: &MongoDB, permissions: Vec<String>) -> Result<UpdateResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<User>(&mongodb.database.collection);
+
+        let _uuid = self.uuid;
+
+        let filter = doc! {
+            "_id": uuid_bson(&_uuid)
+        };
+
+        let update = doc! {
+            "$set": {
+                "permissions": permissions
+            }
+        };
+
+        _coll.update_one(filter, update, None)
+    }
+
+    /// Remove the refresh token hash stored on the user once it was moved to a session
+    fn revoke_token(&self, mongodb: &MongoDB) -> Result<UpdateResult, mongodb::error::Error> {
+        let _coll = mongodb.client.database(&mongodb.database.name).collection::<User>(&mongodb.database.collection);
+
+        let _uuid = self.uuid;
+
+        let filter = doc! {
+            "_id": uuid_bson(&_uuid)
+        };
+
+        let update = doc! {
+            "$unset": {
+                "refresh_token": "",
+                "refresh_hash": ""
+            }
+        };
+
+        _coll.update_one(filter, update, None)
+    }
 }
\ No newline at end of file
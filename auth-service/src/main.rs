@@ -1,10 +1,12 @@
 extern crate env_logger;
 
-use auth_lib::api::authorize::authorize;
-use auth_lib::db::mongo::{Database, MongoDB, connect_mongo};
+use auth_lib::api::{admin::admin, authorize::authorize, discovery::well_known, oauth::oauth};
+use auth_lib::access::{clients::ClientConfig, dpop::DpopConfig, exchange::ExchangeConfig, keyring::KeyRing, keys::KeyConfig, revocation::{MemoryStore, RevocationConfig, Revocations, StoreKind}, roles::RoleConfig, tokens::{Secret, TokenConfig, TokenHasher}};
+use auth_lib::db::{mongo::{Database, MongoDB, connect_mongo}, parser::{device::create_device_indexes, user::migrate_tokens, revocation::MongoStore}};
 
-use envy;
-use actix_web::{self, HttpServer, App};
+use std::time::Duration;
+
+use actix_web::{self, web, HttpServer, App};
 use serde::Deserialize;
 use mongodb::{bson::doc, sync::Client};
 use actix_cors::Cors;
@@ -24,6 +26,50 @@ async fn main() -> std::io::Result<()> {
     let _db = envy::prefixed("DATABASE_")
                     .from_env::<Database>().expect("Please provide DATABASE_URL in .env");
 
+    let secret = envy::prefixed("SECRET__")
+                    .from_env::<Secret>().expect("Please provide SECRET__ACCESS, SECRET__REFRESH, SECRET__HASH and SECRET__SALT in .env");
+
+    // Refresh token hashes are keyed apart from the token signatures
+    if secret.hash == secret.refresh || secret.hash == secret.access {
+        panic!("SECRET__HASH must differ from SECRET__ACCESS and SECRET__REFRESH");
+    }
+
+    let key_config = envy::prefixed("KEYS__")
+                    .from_env::<KeyConfig>().expect("Please provide a valid KEYS__ALGORITHM in .env");
+
+    let token_config = envy::prefixed("TOKEN__")
+                    .from_env::<TokenConfig>().expect("Please provide valid TOKEN__ settings in .env");
+    let token_config = web::Data::new(token_config);
+
+    let client_config = envy::prefixed("OAUTH__")
+                    .from_env::<ClientConfig>().expect("Please provide OAUTH__CLIENTS as client_id:client_secret in .env");
+
+    let exchange_config = envy::prefixed("EXCHANGE__")
+                    .from_env::<ExchangeConfig>().expect("Please provide EXCHANGE__POLICIES as client:audience:scope+scope in .env");
+    let exchange_config = web::Data::new(exchange_config);
+
+    let dpop_config = envy::prefixed("DPOP__")
+                    .from_env::<DpopConfig>().expect("Please provide valid DPOP__ settings in .env");
+    let dpop_config = web::Data::new(dpop_config);
+
+    let role_config = envy::prefixed("ROLES__")
+                    .from_env::<RoleConfig>().expect("Please provide ROLES__HIERARCHY and ROLES__PERMISSIONS as role:name+name in .env");
+    let role_config = web::Data::new(role_config);
+
+    let revocation_config = envy::prefixed("REVOCATION__")
+                    .from_env::<RevocationConfig>().expect("Please provide a valid REVOCATION__STORE in .env");
+
+    // Load the key ring once, it is shared by every worker
+    let ring = match KeyRing::load(&key_config, &secret) {
+        Ok(k) => web::Data::new(k),
+        Err(err) => {
+            panic!("Could not load signing keys: {}", err)
+        }
+    };
+    let key_config = web::Data::new(key_config);
+    let hasher = web::Data::new(TokenHasher::new(&secret));
+    let secret = web::Data::new(secret);
+
     // Connect to DB
     let _client =  connect_mongo(&_db.url);
 
@@ -45,6 +91,42 @@ async fn main() -> std::io::Result<()> {
         database: _db,
     };
 
+    // Refresh tokens were stored in plaintext before, only keep their hash
+    match migrate_tokens(&_mongo, &hasher) {
+        Ok(0) => {}
+        Ok(n) => log::info!("Replaced {} stored refresh tokens by their hash", n),
+        Err(err) => log::warn!("Could not hash the stored refresh tokens: {}", err),
+    }
+
+    // Expired device authorizations are evicted by the database
+    if let Err(err) = create_device_indexes(&_mongo) {
+        log::warn!("Could not index the device authorizations: {}", err);
+    }
+
+    // Clients of the environment are registered, or updated with their secret and redirect URIs
+    for seed in client_config.seeds(&hasher) {
+        if let Err(err) = seed.seed(&_mongo) {
+            log::warn!("Could not register the client {}: {}", seed.client_id, err);
+        }
+    }
+
+    // Revoked access tokens are kept in process, or in the database when several nodes share them
+    let revocations = match revocation_config.store {
+        StoreKind::Memory => Revocations::new(MemoryStore::default()),
+        StoreKind::Mongo => match MongoStore::new(_mongo.clone()) {
+            Ok(store) => Revocations::new(store),
+            Err(err) => {
+                panic!("Could not set up the revocation list: {}", err)
+            }
+        },
+    };
+    let revocations = web::Data::new(revocations);
+
+    // Subscribers also receive the revocations made by other nodes through the shared store
+    if revocation_config.store == StoreKind::Mongo {
+        revocations.clone().into_inner().watch(Duration::from_secs(revocation_config.watch_interval));
+    }
+
     // Start service
     HttpServer::new(move || {
         let cors = Cors::permissive()
@@ -57,7 +139,19 @@ async fn main() -> std::io::Result<()> {
             .app_data(actix_web::web::Data::new(
                 _mongo.clone()
             ))
+            .app_data(ring.clone())
+            .app_data(key_config.clone())
+            .app_data(token_config.clone())
+            .app_data(secret.clone())
+            .app_data(hasher.clone())
+            .app_data(revocations.clone())
+            .app_data(exchange_config.clone())
+            .app_data(dpop_config.clone())
+            .app_data(role_config.clone())
             .service(authorize())
+            .service(well_known())
+            .service(oauth())
+            .service(admin())
     }).bind((config.host, config.port))?
     .run()
     .await